@@ -42,6 +42,6 @@ fn main() {
 
     match trificator(&mut Tokenizer::scanner(&input)) {
         Ok(trees) => for t in trees { t.print(); },
-        Err(e) => println!("{:?}", e)
+        Err(e) => println!("{}", e)
     }
 }
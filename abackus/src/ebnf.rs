@@ -5,13 +5,15 @@ extern crate earlgrey;
 
 use self::lexers::EbnfTokenizer;
 use self::earlgrey::{
-    Grammar, GrammarBuilder,
+    Grammar, GrammarBuilder, Filter,
     EarleyParser, Error, EarleyForest,
 };
 use std::cell::RefCell;
 
 
 // https://en.wikipedia.org/wiki/Extended_Backus%E2%80%93Naur_form
+// Also takes postfix repetition (+ * ?), exclusion (A - B) and char ranges
+// ('a'..'z') as used by W3C specs.
 pub fn ebnf_grammar() -> Grammar {
     GrammarBuilder::default()
       .terminal("<Id>", move |s|
@@ -30,21 +32,35 @@ pub fn ebnf_grammar() -> Grammar {
       .terminal("|", |s| s == "|")
       .terminal("'", |s| s == "'")
       .terminal("\"", |s| s == "\"")
+      .terminal("+", |s| s == "+")
+      .terminal("*", |s| s == "*")
+      .terminal("?", |s| s == "?")
+      .terminal("-", |s| s == "-")
+      .terminal("..", |s| s == "..")
       .nonterm("<RuleList>")
       .nonterm("<Rule>")
       .nonterm("<Body>")
       .nonterm("<Part>")
+      .nonterm("<Term>")
+      .nonterm("<Factor>")
       .nonterm("<Atom>")
       .rule("<RuleList>", &["<RuleList>", "<Rule>"])
       .rule("<RuleList>", &["<Rule>"])
       .rule("<Rule>", &["<Id>", ":=", "<Body>", ";"])
       .rule("<Body>", &["<Body>", "|", "<Part>"])
       .rule("<Body>", &["<Part>"])
-      .rule("<Part>", &["<Part>", "<Atom>"])
-      .rule("<Part>", &["<Atom>"])
+      .rule("<Part>", &["<Part>", "<Term>"])
+      .rule("<Part>", &["<Term>"])
+      .rule("<Term>", &["<Factor>", "-", "<Factor>"])
+      .rule("<Term>", &["<Factor>"])
+      .rule("<Factor>", &["<Factor>", "+"])
+      .rule("<Factor>", &["<Factor>", "*"])
+      .rule("<Factor>", &["<Factor>", "?"])
+      .rule("<Factor>", &["<Atom>"])
       .rule("<Atom>", &["<Id>"])
       .rule("<Atom>", &["'", "<Chars>", "'"])
       .rule("<Atom>", &["\"", "<Chars>", "\""])
+      .rule("<Atom>", &["'", "<Chars>", "'", "..", "'", "<Chars>", "'"])
       .rule("<Atom>", &["[", "<Body>", "]"])
       .rule("<Atom>", &["{", "<Body>", "}"])
       .rule("<Atom>", &["(", "<Body>", ")"])
@@ -85,7 +101,7 @@ impl ParserBuilder {
                     debug!("Adding terminal {:?}", token);
                     let tok = token.to_string();
                     gb.borrow_mut()
-                        .add_terminal(token, move |s| s == tok, true);
+                        .add_terminal(token, move |s: &String| *s == tok, true);
                 },
                 _ => ()
             }
@@ -101,7 +117,7 @@ impl ParserBuilder {
             let mut t_gb = gb.borrow_mut();
             for rule in body {
                 debug!("Adding rule {:?} -> {:?}", id, rule);
-                t_gb.add_rule(id.as_ref(), rule.as_slice());
+                t_gb.add_rule(id.as_str(), rule.as_slice());
             }
             G::Nop
         });
@@ -120,14 +136,73 @@ impl ParserBuilder {
     }
 
     fn action_part<'a>(ev: &mut EarleyForest<'a, G>) {
-        ev.action("<Part> -> <Part> <Atom>", |mut n| {
+        ev.action("<Part> -> <Part> <Term>", |mut n| {
             let mut part = pull!(G::Part, n.remove(0));
             part.push(pull!(G::Atom, n.remove(0)));
             G::Part(part)
         });
-        ev.action("<Part> -> <Atom>", |mut n| {
+        ev.action("<Part> -> <Term>", |mut n| {
             G::Part(vec!(pull!(G::Atom, n.remove(0))))
         });
+        ev.action("<Term> -> <Factor>", |mut n| n.remove(0));
+        ev.action("<Factor> -> <Atom>", |mut n| n.remove(0));
+    }
+
+    fn action_postfix<'a>(ev: &mut EarleyForest<'a, G>,
+                          gb: &'a RefCell<GrammarBuilder>) {
+        // <Factor> -> aux, a template instance (eg: X* is aux -> <e> | aux X)
+        ev.action("<Factor> -> <Factor> +", move |mut n| {
+            let factor = pull!(G::Atom, n.remove(0));
+            G::Atom(gb.borrow_mut().add_many1(&factor))
+        });
+        ev.action("<Factor> -> <Factor> *", move |mut n| {
+            let factor = pull!(G::Atom, n.remove(0));
+            G::Atom(gb.borrow_mut().add_many(&factor))
+        });
+        ev.action("<Factor> -> <Factor> ?", move |mut n| {
+            let factor = pull!(G::Atom, n.remove(0));
+            G::Atom(gb.borrow_mut().add_optional(&factor))
+        });
+    }
+
+    fn action_exclusion<'a>(ev: &mut EarleyForest<'a, G>,
+                            gb: &'a RefCell<GrammarBuilder>) {
+        ev.action("<Term> -> <Factor> - <Factor>", move |mut n| {
+            // <Term> -> aux ; aux -> A | B ; spans matching B are rejected
+            let aux = gb.borrow().unique_symbol_name();
+            debug!("Adding non-term {:?}", aux);
+            let mut t_gb = gb.borrow_mut();
+            t_gb.add_nonterm(aux.as_str(), false);
+            let exclude = pull!(G::Atom, n.remove(2));
+            let factor = pull!(G::Atom, n.remove(0));
+            t_gb.add_rule(aux.as_str(), &[factor]);
+            t_gb.add_rule(aux.as_str(), &[exclude.as_str()]);
+            t_gb.add_filter(
+                Filter::Reject(format!("{} -> {}", aux, exclude)));
+            G::Atom(aux)
+        });
+    }
+
+    fn action_range<'a>(ev: &mut EarleyForest<'a, G>,
+                        gb: &'a RefCell<GrammarBuilder>) {
+        // a terminal matching tokens of one char between both (inclusive)
+        ev.try_action("<Atom> -> ' <Chars> ' .. ' <Chars> '", move |n| {
+            let bound = |atom: &G| match *atom {
+                G::Atom(ref c) if c.chars().count() == 1 => c.chars().next(),
+                _ => None,
+            };
+            let (lo, hi) = (bound(&n[1])?, bound(&n[5])?);
+            let name = format!("'{}'..'{}'", lo, hi);
+            debug!("Adding terminal {:?}", name);
+            gb.borrow_mut().add_terminal(name.as_str(), move |s: &String| {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => lo <= c && c <= hi,
+                    _ => false,
+                }
+            }, true);
+            Some(G::Atom(name))
+        });
     }
 
     fn action_grouping<'a>(ev: &mut EarleyForest<'a, G>,
@@ -136,11 +211,11 @@ impl ParserBuilder {
             let aux = gb.borrow().unique_symbol_name();
             debug!("Adding non-term {:?}", aux);
             let mut t_gb = gb.borrow_mut();
-            t_gb.add_nonterm(aux.as_ref(), false);
+            t_gb.add_nonterm(aux.as_str(), false);
             let body = pull!(G::Body, n.remove(1));
             for rule in body {
                 debug!("Adding rule {:?} -> {:?}", aux, rule);
-                t_gb.add_rule(aux.as_ref(), rule.as_slice());
+                t_gb.add_rule(aux.as_str(), rule.as_slice());
             }
             G::Atom(aux)
         });
@@ -153,13 +228,13 @@ impl ParserBuilder {
             let aux = gb.borrow().unique_symbol_name();
             debug!("Adding non-term {:?}", aux);
             let mut t_gb = gb.borrow_mut();
-            t_gb.add_nonterm(aux.as_ref(), false);
+            t_gb.add_nonterm(aux.as_str(), false);
             let body = pull!(G::Body, n.remove(1));
             for rule in body {
                 debug!("Adding rule {:?} -> {:?}", aux, rule);
-                t_gb.add_rule(aux.as_ref(), rule.as_slice());
+                t_gb.add_rule(aux.as_str(), rule.as_slice());
                 debug!("Adding rule {:?} -> []", aux);
-                t_gb.add_rule::<_, String>(aux.as_ref(), &[]);
+                t_gb.add_rule::<_, String>(aux.as_str(), &[]);
             }
             G::Atom(aux)
         });
@@ -172,14 +247,14 @@ impl ParserBuilder {
             let aux = gb.borrow().unique_symbol_name();
             debug!("Adding non-term {:?}", aux);
             let mut t_gb = gb.borrow_mut();
-            t_gb.add_nonterm(aux.as_ref(), false);
+            t_gb.add_nonterm(aux.as_str(), false);
             let body = pull!(G::Body, n.remove(1));
             for mut rule in body {
                 rule.push(aux.clone());
                 debug!("Adding rule {:?} -> {:?}", aux, rule);
-                t_gb.add_rule(aux.as_ref(), rule.as_slice());
+                t_gb.add_rule(aux.as_str(), rule.as_slice());
                 debug!("Adding rule {:?} -> []", aux);
-                t_gb.add_rule::<_, String>(aux.as_ref(), &[]);
+                t_gb.add_rule::<_, String>(aux.as_str(), &[]);
             }
             G::Atom(aux)
         });
@@ -199,6 +274,9 @@ impl ParserBuilder {
             ParserBuilder::action_grouping(&mut ev, &user_grammar_builder);
             ParserBuilder::action_optional(&mut ev, &user_grammar_builder);
             ParserBuilder::action_repeat(&mut ev, &user_grammar_builder);
+            ParserBuilder::action_postfix(&mut ev, &user_grammar_builder);
+            ParserBuilder::action_exclusion(&mut ev, &user_grammar_builder);
+            ParserBuilder::action_range(&mut ev, &user_grammar_builder);
             ev.action("<Atom> -> <Id>", |mut n| n.remove(0));
             ev.action("<Atom> -> ' <Chars> '", |mut n| n.remove(1));
             ev.action("<Atom> -> \" <Chars> \"", |mut n| n.remove(1));
@@ -207,8 +285,11 @@ impl ParserBuilder {
             // Use EBNF parser to parse the user provided grammar
             let state = ebnf.parse(EbnfTokenizer::scanner(user_grammar_spec))?;
             // Forge user's grammar builder by executing semantic actions
-            if ev.eval_all(&state)?.len() != 1 {
-                panic!("BUG: EBNF grammar shouldn't be ambiguous!");
+            // (char ranges must be one char each or the tree is rejected)
+            match ev.eval_all(&state)?.len() {
+                0 => return Err(Error::Rejected("<RuleList>".to_string())),
+                1 => (),
+                _ => panic!("BUG: EBNF grammar shouldn't be ambiguous!"),
             }
         }
         // User's GrammarBuilder has all rules and non-terminals from the spec
@@ -217,8 +298,9 @@ impl ParserBuilder {
 
     // Plug-in functions that parse Terminals before we build the grammar
     pub fn plug_terminal<N, F>(mut self, name: N, pred: F) -> Self
-            where N: Into<String>, F: 'static + Fn(&str)->bool {
-        self.0.add_terminal(name.into().as_ref(), pred, false);
+            where N: Into<String>, F: 'static + Send + Sync + Fn(&str)->bool {
+        self.0.add_terminal(name.into(),
+                            move |s: &String| pred(s), false);
         ParserBuilder(self.0)
     }
 
@@ -189,3 +189,62 @@ fn plug_terminal() {
                 r#"Leaf("Number", "1")])"#)
     ]);
 }
+
+#[test]
+fn postfix_repetition() {
+    let g = r#"
+        args := "(" b+ ")" "!"? "c"* ;
+        b := "0" | "1" ;
+    "#;
+    let parser = ParserBuilder::default().treeficator(&g, "args");
+    let trees = parser("( 1 0 ) !".split_whitespace()).unwrap();
+    check_trees(&trees, vec![
+        concat!(
+            r#"Node("args -> ( <Uniq-3> ) <Uniq-6> <Uniq-8>", ["#,
+                r#"Leaf("(", "("), "#,
+                r#"Node("<Uniq-3> -> <Uniq-3> b", ["#,
+                    r#"Node("<Uniq-3> -> b", ["#,
+                        r#"Node("b -> 1", [Leaf("1", "1")])]), "#,
+                    r#"Node("b -> 0", [Leaf("0", "0")])]), "#,
+                r#"Leaf(")", ")"), "#,
+                r#"Node("<Uniq-6> -> !", [Leaf("!", "!")]), "#,
+                r#"Node("<Uniq-8> -> ", [])])"#)
+    ]);
+    assert!(parser("( 1 ) c c".split_whitespace()).is_ok());
+    assert!(parser("( ) !".split_whitespace()).is_err());
+    assert!(parser("( 1 ) ! !".split_whitespace()).is_err());
+}
+
+#[test]
+fn exclusion() {
+    let g = r#"
+        stmt := "let" (id - keyword) ;
+        keyword := "let" | "if" ;
+    "#;
+    let parser = ParserBuilder::default()
+        .plug_terminal("id", |s| s.chars().all(|c| c.is_alphabetic()))
+        .treeficator(&g, "stmt");
+    let trees = parser("let x".split_whitespace()).unwrap();
+    check_trees(&trees, vec![
+        concat!(
+            r#"Node("stmt -> let <Uniq-5>", ["#,
+                r#"Leaf("let", "let"), "#,
+                r#"Node("<Uniq-5> -> <Uniq-4>", ["#,
+                    r#"Node("<Uniq-4> -> id", [Leaf("id", "x")])])])"#)
+    ]);
+    assert!(parser("let if".split_whitespace()).is_err());
+}
+
+#[test]
+fn char_range() {
+    let g = r#"
+        hex := ('0'..'9' | 'a'..'f')+ ;
+    "#;
+    let parser = ParserBuilder::default().sexprificator(&g, "hex");
+    assert!(parser("f 0 a".split_whitespace()).is_ok());
+    assert!(parser("g".split_whitespace()).is_err());
+    assert!(parser("0a".split_whitespace()).is_err());
+
+    let g = r#" hex := 'a'..'fg' ; "#;
+    assert!(ParserBuilder::default().into_parser("hex", g).is_err());
+}
@@ -60,7 +60,8 @@ impl ParserBuilder {
         }
         // 3. make function that parses strings into trees
         let parser = EarleyParser::new(grammar);
-        move |tokenizer| tree_builder.eval_all(&parser.parse(tokenizer)?)
+        move |tokenizer| tree_builder.eval_all(&parser.parse(
+            tokenizer.map(|t| t.as_ref().to_string()))?)
     }
 
     pub fn sexprificator<S, SI>(self, grammar: &str, start: &str)
@@ -84,6 +85,7 @@ impl ParserBuilder {
         }
         // 3. make function that parses strings into trees
         let parser = EarleyParser::new(grammar);
-        move |tokenizer| tree_builder.eval_all(&parser.parse(tokenizer)?)
+        move |tokenizer| tree_builder.eval_all(&parser.parse(
+            tokenizer.map(|t| t.as_ref().to_string()))?)
     }
 }
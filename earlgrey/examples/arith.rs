@@ -104,7 +104,7 @@ fn main() {
     let evaler = semanter();
     for expr in input {
         match parser.parse(&mut Tokenizer::scanner(&expr)) {
-            Err(e) => println!("Parse err: {:?}", e),
+            Err(e) => println!("Parse err: {}", e),
             Ok(state) => {
                 rl.borrow_mut().add_history_entry(&expr);
                 let val = evaler.eval(&state);
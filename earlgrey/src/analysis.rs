@@ -0,0 +1,233 @@
+#![deny(warnings)]
+
+use grammar::{Grammar, Symbol};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+
+// Findings of Grammar::analyze, they don't stop a grammar from parsing but
+// usually point at mistakes (eg: typos in generated grammars)
+#[derive(Debug,PartialEq)]
+pub enum Diagnostic {
+    // non-terminal that can't be derived from the start symbol
+    Unreachable(String),
+    // non-terminal that never derives a string of terminals
+    Unproductive(String),
+    // non-terminal that derives the empty string
+    Nullable(String),
+    // non-terminals deriving each other without consuming input
+    // (eg: A -> B, B -> A) which makes the number of parse trees infinite
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Diagnostic::Unreachable(ref sym) =>
+                write!(f, "Unreachable symbol: {}", sym),
+            Diagnostic::Unproductive(ref sym) =>
+                write!(f, "Unproductive symbol: {}", sym),
+            Diagnostic::Nullable(ref sym) =>
+                write!(f, "Nullable symbol: {}", sym),
+            Diagnostic::Cycle(ref syms) =>
+                write!(f, "Cyclic symbols: {}", syms.join(" ")),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl<T> Grammar<T> {
+    // Every non-terminal used by the grammar, including ones without rules
+    fn nonterms(&self) -> BTreeSet<&str> {
+        let mut nonterms = BTreeSet::new();
+        nonterms.insert(self.start.as_str());
+        for rule in &self.rules {
+            nonterms.insert(rule.head.as_str());
+            nonterms.extend(rule.spec.iter().filter_map(|s| match **s {
+                Symbol::NonTerm(ref name) => Some(name.as_str()),
+                Symbol::Terminal(_, _) => None,
+            }));
+        }
+        nonterms
+    }
+
+    fn reachable(&self) -> BTreeSet<&str> {
+        let mut reachable = BTreeSet::new();
+        let mut pending = vec![self.start.as_str()];
+        while let Some(head) = pending.pop() {
+            if !reachable.insert(head) { continue; }
+            for rule in self.rules.iter().filter(|r| r.head == head) {
+                pending.extend(rule.spec.iter().filter_map(|s| match **s {
+                    Symbol::NonTerm(ref name) => Some(name.as_str()),
+                    Symbol::Terminal(_, _) => None,
+                }));
+            }
+        }
+        reachable
+    }
+
+    // Same fixpoint as nullable symbols but terminals count as productive
+    fn productive(&self) -> BTreeSet<&str> {
+        let mut productive = BTreeSet::new();
+        loop {
+            let new: Vec<_> = self.rules.iter()
+                .filter(|r| !productive.contains(r.head.as_str()))
+                .filter(|r| r.spec.iter().all(|s| match **s {
+                    Symbol::NonTerm(ref name) => productive.contains(name.as_str()),
+                    Symbol::Terminal(_, _) => true,
+                }))
+                .map(|r| r.head.as_str())
+                .collect();
+            if new.is_empty() { return productive; }
+            productive.extend(new);
+        }
+    }
+
+    // Groups of non-terminals that derive each other. A derives B without
+    // consuming input when A -> x B y and x, y are nullable.
+    fn cycles(&self) -> Vec<Vec<String>> {
+        let mut derives: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+        for rule in &self.rules {
+            for (idx, symbol) in rule.spec.iter().enumerate() {
+                let others_nullable = rule.spec.iter().enumerate()
+                    .filter(|&(i, _)| i != idx)
+                    .all(|(_, s)| self.is_nullable(s.name()));
+                if let Symbol::NonTerm(ref name) = **symbol {
+                    if others_nullable {
+                        derives.entry(rule.head.as_str()).or_default()
+                            .insert(name.as_str());
+                    }
+                }
+            }
+        }
+        // symbols reachable through one or more derivations
+        let closure: BTreeMap<&str, BTreeSet<&str>> = derives.keys()
+            .map(|&from| {
+                let mut seen = BTreeSet::new();
+                let mut pending: Vec<&str> =
+                    derives[from].iter().cloned().collect();
+                while let Some(sym) = pending.pop() {
+                    if seen.insert(sym) {
+                        if let Some(next) = derives.get(sym) {
+                            pending.extend(next.iter().cloned());
+                        }
+                    }
+                }
+                (from, seen)
+            })
+            .collect();
+        let mut cycles: Vec<Vec<String>> = Vec::new();
+        for (&sym, seen) in &closure {
+            if !seen.contains(sym) { continue; }
+            // symbols on a cycle with 'sym' (sorted, so 'sym' may not be first)
+            let cycle: Vec<String> = seen.iter()
+                .filter(|&&other|
+                        matches!(closure.get(other), Some(s) if s.contains(sym)))
+                .map(|s| s.to_string())
+                .collect();
+            if !cycles.contains(&cycle) {
+                cycles.push(cycle);
+            }
+        }
+        cycles
+    }
+
+    // Check the grammar for unreachable, unproductive, nullable and cyclic
+    // symbols. Diagnostics are grouped by kind and sorted by symbol name.
+    pub fn analyze(&self) -> Vec<Diagnostic> {
+        let nonterms = self.nonterms();
+        let reachable = self.reachable();
+        let productive = self.productive();
+        let mut nullable: Vec<_> = self.nullable.iter().collect();
+        nullable.sort();
+        let mut diagnostics = Vec::new();
+        diagnostics.extend(nonterms.iter()
+            .filter(|s| !reachable.contains(*s))
+            .map(|s| Diagnostic::Unreachable(s.to_string())));
+        diagnostics.extend(nonterms.iter()
+            .filter(|s| !productive.contains(*s))
+            .map(|s| Diagnostic::Unproductive(s.to_string())));
+        diagnostics.extend(nullable.into_iter()
+            .map(|s| Diagnostic::Nullable(s.to_string())));
+        diagnostics.extend(self.cycles().into_iter().map(Diagnostic::Cycle));
+        diagnostics
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::GrammarBuilder;
+    use super::Diagnostic;
+
+    #[test]
+    fn clean_grammar() {
+        // Sum -> Sum + Num | Num
+        let g = GrammarBuilder::default()
+            .nonterm("Sum")
+            .terminal("Num", |n| n.chars().all(|c| "123".contains(c)))
+            .terminal("+", |n| n == "+")
+            .rule("Sum", &["Sum", "+", "Num"])
+            .rule("Sum", &["Num"])
+            .into_grammar("Sum")
+            .unwrap();
+        assert!(g.analyze().is_empty());
+    }
+
+    #[test]
+    fn unreachable_unproductive() {
+        // S -> A x | x ; A -> A x ; B -> x | C
+        let g = GrammarBuilder::default()
+            .nonterm("S")
+            .nonterm("A")
+            .nonterm("B")
+            .nonterm("C")
+            .terminal("x", |n| n == "x")
+            .rule("S", &["A", "x"])
+            .rule("S", &["x"])
+            .rule("A", &["A", "x"])
+            .rule("B", &["x"])
+            .rule("B", &["C"])
+            .into_grammar("S")
+            .unwrap();
+        assert_eq!(g.analyze(), vec![
+            Diagnostic::Unreachable("B".to_string()),
+            Diagnostic::Unreachable("C".to_string()),
+            Diagnostic::Unproductive("A".to_string()),
+            Diagnostic::Unproductive("C".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn unit_cycles() {
+        // S -> A ; A -> B | x ; B -> A | C B ; C -> <e> ; D -> D
+        let g = GrammarBuilder::default()
+            .nonterm("S")
+            .nonterm("A")
+            .nonterm("B")
+            .nonterm("C")
+            .nonterm("D")
+            .terminal("x", |n| n == "x")
+            .rule("S", &["A"])
+            .rule("S", &["D"])
+            .rule("A", &["B"])
+            .rule("A", &["x"])
+            .rule("B", &["A"])
+            .rule("B", &["C", "B"])
+            .rule::<_, &str>("C", &[])
+            .rule("D", &["D"])
+            .into_grammar("S")
+            .unwrap();
+        assert_eq!(g.analyze(), vec![
+            Diagnostic::Unproductive("D".to_string()),
+            Diagnostic::Nullable("C".to_string()),
+            Diagnostic::Cycle(vec!["A".to_string(), "B".to_string()]),
+            Diagnostic::Cycle(vec!["D".to_string()]),
+        ]);
+        assert_eq!(format!("{}", Diagnostic::Cycle(
+                    vec!["A".to_string(), "B".to_string()])),
+                   "Cyclic symbols: A B");
+    }
+}
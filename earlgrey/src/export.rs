@@ -0,0 +1,256 @@
+#![deny(warnings)]
+
+use items::{Item, StateSet, Trigger};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::rc::Rc;
+
+
+// A back-pointer of an exported item: (source item, completed item) or
+// (source item, debug format of the scanned token)
+#[derive(PartialEq,Eq,PartialOrd,Ord)]
+pub enum Edge {
+    Complete(usize, usize),
+    Scan(usize, String),
+}
+
+// Items and their back-pointers numbered in the order they're found.
+// Sources are visited sorted so the output is the same across runs.
+pub struct Graph<T> {
+    index: HashMap<Rc<Item<T>>, usize>,
+    items: Vec<Rc<Item<T>>>,
+    edges: Vec<Vec<Edge>>,
+    roots: Vec<usize>,
+}
+
+fn item_key<T>(item: &Item<T>) -> (usize, usize, String, usize) {
+    (item.start, item.end, item.rule.to_string(), item.dot)
+}
+
+fn label<T>(item: &Item<T>) -> String {
+    let pre = item.rule.spec.iter().take(item.dot)
+        .map(|s| s.name()).collect::<Vec<_>>();
+    let post = item.rule.spec.iter().skip(item.dot)
+        .map(|s| s.name()).collect::<Vec<_>>();
+    format!("{} -> {} \u{00b7} {} ({}-{})", item.rule.head,
+            pre.join(" "), post.join(" "), item.start, item.end)
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn json_escape(s: &str) -> String {
+    let mut escaped = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 =>
+                escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl<T: fmt::Debug> Graph<T> {
+    fn new() -> Self {
+        Graph{index: HashMap::new(), items: Vec::new(),
+              edges: Vec::new(), roots: Vec::new()}
+    }
+
+    fn add(&mut self, item: &Rc<Item<T>>) -> usize {
+        if let Some(&id) = self.index.get(item) {
+            return id;
+        }
+        self.items.push(item.clone());
+        self.index.insert(item.clone(), self.items.len() - 1);
+        self.items.len() - 1
+    }
+
+    // Follow back-pointers of items added so far and the ones they lead to
+    fn walk(&mut self) {
+        while self.edges.len() < self.items.len() {
+            let item = self.items[self.edges.len()].clone();
+            let mut sources: Vec<_> = item.source().iter().cloned().collect();
+            sources.sort_by_key(|(src, trig)| (item_key(src), match *trig {
+                Trigger::Complete(ref t) => Some(item_key(t)),
+                Trigger::Scan(_) => None,
+            }));
+            let mut edges: Vec<Edge> = sources.iter()
+                .map(|(src, trig)| {
+                    let src = self.add(src);
+                    match *trig {
+                        Trigger::Complete(ref t) =>
+                            Edge::Complete(src, self.add(t)),
+                        Trigger::Scan(ref token) =>
+                            Edge::Scan(src, format!("{:?}", token)),
+                    }
+                })
+                .collect();
+            edges.sort();
+            self.edges.push(edges);
+        }
+    }
+
+    // Every item of the chart, including ones rebuilt from Leo paths
+    pub fn chart(states: &[StateSet<T>], roots: &[Rc<Item<T>>]) -> Self {
+        let mut graph = Graph::new();
+        for stateset in states {
+            let mut items: Vec<_> = stateset.iter().collect();
+            items.sort_by_key(|item| item_key(item));
+            for item in items {
+                graph.add(item);
+            }
+        }
+        graph.roots = roots.iter().map(|root| graph.add(root)).collect();
+        graph.roots.sort();
+        graph.walk();
+        graph
+    }
+
+    // Items reachable from complete parses, ie: the back-pointer forest
+    pub fn forest(roots: &[Rc<Item<T>>]) -> Self {
+        let mut graph = Graph::new();
+        let mut roots: Vec<_> = roots.iter().collect();
+        roots.sort_by_key(|item| item_key(item));
+        graph.roots = roots.into_iter().map(|root| graph.add(root)).collect();
+        graph.walk();
+        graph
+    }
+
+    // Items are grouped by StateSet (the position where they end). Each
+    // back-pointer is a point linking to its source (dashed) and trigger,
+    // items with more than one are ambiguous and drawn in red.
+    pub fn to_dot(&self) -> String {
+        let mut by_set = BTreeMap::new();
+        for (id, item) in self.items.iter().enumerate() {
+            by_set.entry(item.end).or_insert_with(Vec::new).push(id);
+        }
+        let mut dot = String::from("digraph earley {\n  rankdir=RL;\n");
+        for (set, ids) in by_set {
+            dot.push_str(&format!(
+                "  subgraph cluster_{} {{\n    label=\"S{}\";\n", set, set));
+            for id in ids {
+                let item = &self.items[id];
+                let mut attrs = format!("label=\"{}\"",
+                                        dot_escape(&label(item)));
+                if item.complete() { attrs.push_str(", shape=box"); }
+                if self.edges[id].len() > 1 { attrs.push_str(", color=red"); }
+                if self.roots.contains(&id) { attrs.push_str(", penwidth=2"); }
+                dot.push_str(&format!("    i{} [{}];\n", id, attrs));
+            }
+            dot.push_str("  }\n");
+        }
+        let mut tokens = BTreeMap::new();
+        for (id, edges) in self.edges.iter().enumerate() {
+            for (n, edge) in edges.iter().enumerate() {
+                dot.push_str(&format!("  p{}_{} [shape=point];\n", id, n));
+                dot.push_str(&format!("  i{} -> p{}_{};\n", id, id, n));
+                match *edge {
+                    Edge::Complete(src, trig) => {
+                        dot.push_str(&format!(
+                            "  p{}_{} -> i{} [style=dashed];\n", id, n, src));
+                        dot.push_str(&format!(
+                            "  p{}_{} -> i{};\n", id, n, trig));
+                    },
+                    Edge::Scan(src, ref token) => {
+                        let pos = self.items[src].end;
+                        tokens.insert(pos, token.clone());
+                        dot.push_str(&format!(
+                            "  p{}_{} -> i{} [style=dashed];\n", id, n, src));
+                        dot.push_str(&format!(
+                            "  p{}_{} -> t{};\n", id, n, pos));
+                    },
+                }
+            }
+        }
+        for (pos, token) in tokens {
+            dot.push_str(&format!("  t{} [shape=plaintext, label=\"{}\"];\n",
+                                  pos, dot_escape(&token)));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    // {"items": [{"id", "rule", "dot", "start", "end", "sources"}, ..],
+    //  "roots": [..]} with sources as {"source", "complete"|"scan"}
+    pub fn to_json(&self) -> String {
+        let items: Vec<String> = self.items.iter().enumerate()
+            .map(|(id, item)| {
+                let sources: Vec<String> = self.edges[id].iter()
+                    .map(|edge| match *edge {
+                        Edge::Complete(src, trig) =>
+                            format!("{{\"source\": {}, \"complete\": {}}}",
+                                    src, trig),
+                        Edge::Scan(src, ref token) =>
+                            format!("{{\"source\": {}, \"scan\": \"{}\"}}",
+                                    src, json_escape(token)),
+                    })
+                    .collect();
+                format!("{{\"id\": {}, \"rule\": \"{}\", \"dot\": {}, \
+                         \"start\": {}, \"end\": {}, \"sources\": [{}]}}",
+                        id, json_escape(&item.rule.to_string()), item.dot,
+                        item.start, item.end, sources.join(", "))
+            })
+            .collect();
+        let roots: Vec<String> =
+            self.roots.iter().map(|r| r.to_string()).collect();
+        format!("{{\"items\": [\n  {}\n], \"roots\": [{}]}}\n",
+                items.join(",\n  "), roots.join(", "))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::GrammarBuilder;
+    use parser::EarleyParser;
+    use super::json_escape;
+
+    fn parser() -> EarleyParser {
+        // S -> S S | b
+        let grammar = GrammarBuilder::default()
+          .nonterm("S")
+          .terminal("b", |n| n == "b")
+          .rule("S", &["S", "S"])
+          .rule("S", &["b"])
+          .into_grammar("S")
+          .expect("Bad grammar");
+        EarleyParser::new(grammar)
+    }
+
+    #[test]
+    fn forest_dot() {
+        let ptrees = parser().parse("b b b".split_whitespace()).unwrap();
+        let dot = ptrees.to_dot();
+        assert!(dot.starts_with("digraph earley {"));
+        // the root splits 'b b b' two ways
+        assert!(dot.contains(
+            "[label=\"S -> S S \u{00b7}  (0-3)\", shape=box, color=red, \
+             penwidth=2]"));
+        assert!(dot.contains("[shape=plaintext, label=\"\\\"b\\\"\"]"));
+        assert_eq!(dot, ptrees.to_dot());
+    }
+
+    #[test]
+    fn chart_json() {
+        let p = parser();
+        let mut session = p.session();
+        session.push("b").unwrap();
+        // failed pushes leave the chart as it was
+        assert!(session.push("x").is_err());
+        let json = session.to_json();
+        assert!(json.contains("{\"id\": 0, \"rule\": \"S -> S S\", \"dot\": 0, \
+                              \"start\": 0, \"end\": 0, \"sources\": []}"));
+        assert!(json.contains("\"scan\": \"\\\"b\\\"\""));
+        assert!(!json.ends_with("\"roots\": []}\n"));
+        assert_eq!(json_escape("a\"\\\n\u{1}"), "a\\\"\\\\\\n\\u0001");
+    }
+}
@@ -0,0 +1,324 @@
+#![deny(warnings)]
+
+use grammar::{Rule, RuleId, Symbol};
+use items::{Item, StateSet, Trigger};
+use parser::Error;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+
+
+// How rules of a group nest into each other, see GrammarBuilder::left_assoc
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Assoc {
+    Left,
+    Right,
+    Non,
+}
+
+// Filters as declared on GrammarBuilder, rules go by their string
+// representation (eg: "E -> E + E")
+#[derive(Clone,Debug,PartialEq)]
+pub enum Filter {
+    // rules of the first group bind tighter than rules of the second
+    Priority(Vec<String>, Vec<String>),
+    Assoc(Assoc, Vec<String>),
+    // spans matched by the rule can't be derived by its head at all
+    Reject(String),
+    // spans matched by the rule can't be derived by other rules of its head
+    Prefer(String),
+}
+
+// Disambiguation filters of a grammar, they prune the back-pointer forest
+// of a parse so every way of evaluating it (eg: EarleyForest::eval or the
+// Sppf) only sees the trees left.
+#[derive(Clone,Debug,Default)]
+pub struct Filters {
+    // as declared, they're resolved into the rest
+    declared: Vec<Filter>,
+    // (parent rule, position in its spec, child rule) that can't be nested
+    forbidden: HashSet<(RuleId, usize, RuleId)>,
+    reject: HashSet<RuleId>,
+    prefer: HashSet<RuleId>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl Filters {
+    // Resolve declared filters against the rules of a grammar
+    pub fn new<T>(declared: &[Filter], rules: &[Arc<Rule<T>>])
+            -> Result<Filters, Error> {
+        let find = |name: &str| rules.iter()
+            .find(|r| r.to_string() == name)
+            .cloned()
+            .ok_or_else(|| Error::MissingRule(name.to_string()));
+        let mut filters = Filters{declared: declared.to_vec(),
+                                  ..Filters::default()};
+        let mut priorities = HashSet::new();
+        for filter in declared {
+            match *filter {
+                Filter::Priority(ref higher, ref lower) => {
+                    for h in higher {
+                        for l in lower {
+                            priorities.insert((find(h)?.id, find(l)?.id));
+                        }
+                    }
+                },
+                Filter::Assoc(assoc, ref group) => {
+                    let group = group.iter().map(|name| find(name))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    let (left, right) = match assoc {
+                        Assoc::Left => (false, true),
+                        Assoc::Right => (true, false),
+                        Assoc::Non => (true, true),
+                    };
+                    for parent in &group {
+                        for child in &group {
+                            filters.forbid(parent, child, left, right);
+                        }
+                    }
+                },
+                Filter::Reject(ref name) => {
+                    filters.reject.insert(find(name)?.id);
+                },
+                Filter::Prefer(ref name) => {
+                    filters.prefer.insert(find(name)?.id);
+                },
+            }
+        }
+        // a > b and b > c means a > c
+        loop {
+            let new: Vec<_> = priorities.iter()
+                .flat_map(|&(a, b)| priorities.iter()
+                          .filter(move |&&(c, _)| c == b)
+                          .map(move |&(_, d)| (a, d)))
+                .filter(|pair| !priorities.contains(pair))
+                .collect();
+            if new.is_empty() { break; }
+            priorities.extend(new);
+        }
+        for (higher, lower) in priorities {
+            filters.forbid(&rules[higher.0], &rules[lower.0], true, true);
+        }
+        Ok(filters)
+    }
+
+    // 'child' can't be the leftmost and/or rightmost symbol of 'parent'
+    fn forbid<T>(&mut self, parent: &Rule<T>, child: &Rule<T>,
+                 left: bool, right: bool) {
+        let last = parent.spec.len().saturating_sub(1);
+        for (pos, symbol) in parent.spec.iter().enumerate() {
+            if !(left && pos == 0 || right && pos == last) { continue; }
+            if let Symbol::NonTerm(ref name) = **symbol {
+                if *name == child.head {
+                    self.forbidden.insert((parent.id, pos, child.id));
+                }
+            }
+        }
+    }
+
+    pub fn declared(&self) -> &[Filter] { &self.declared }
+
+    pub fn is_empty(&self) -> bool {
+        self.forbidden.is_empty() && self.reject.is_empty() &&
+            self.prefer.is_empty()
+    }
+
+    fn allowed<T>(&self, item: &Item<T>, source: &Rc<Item<T>>,
+                  trigger: &Trigger<T>, alive: &HashSet<Rc<Item<T>>>) -> bool {
+        alive.contains(source) && match *trigger {
+            Trigger::Complete(ref child) => alive.contains(child) &&
+                !self.forbidden.contains(
+                    &(item.rule.id, source.dot, child.rule.id)),
+            Trigger::Scan(_) => true,
+        }
+    }
+
+    // Copy of the forest under 'roots' keeping only back-pointers the
+    // filters allow. Items left without back-pointers are dropped, and so
+    // are the roots that can't be derived anymore.
+    pub fn apply<T>(&self, states: &[StateSet<T>], roots: Vec<Rc<Item<T>>>)
+            -> Vec<Rc<Item<T>>> {
+        if self.is_empty() {
+            return roots;
+        }
+        // items of the forest, smaller ones first to settle them sooner
+        let mut forest: Vec<Rc<Item<T>>> = Vec::new();
+        let mut seen = HashSet::new();
+        let mut pending = roots.clone();
+        while let Some(item) = pending.pop() {
+            if !seen.insert(item.clone()) { continue; }
+            for (src, trig) in item.source().iter() {
+                pending.push(src.clone());
+                if let Trigger::Complete(ref child) = *trig {
+                    pending.push(child.clone());
+                }
+            }
+            forest.push(item);
+        }
+        forest.sort_by_key(|item| (item.end - item.start, item.dot));
+        // spans (head, start, end) matched by a rejected or preferred rule,
+        // completions skipped by Leo's optimization are only in the forest
+        let mut rejected = HashSet::new();
+        let mut preferred = HashSet::new();
+        let chart = states.iter().flat_map(|s| s.iter());
+        for item in chart.chain(forest.iter()) {
+            if !item.complete() { continue; }
+            let span = (item.rule.head.as_str(), item.start, item.end);
+            if self.reject.contains(&item.rule.id) { rejected.insert(span); }
+            if self.prefer.contains(&item.rule.id) { preferred.insert(span); }
+        }
+        let kept = |item: &Item<T>| {
+            let span = (item.rule.head.as_str(), item.start, item.end);
+            !item.complete() || !rejected.contains(&span) &&
+                (self.prefer.contains(&item.rule.id) ||
+                 !preferred.contains(&span))
+        };
+        // derivable items: predictions or items with an allowed back-pointer
+        let mut alive = HashSet::new();
+        loop {
+            let before = alive.len();
+            for item in &forest {
+                if alive.contains(item) || !kept(item) { continue; }
+                let sources = item.source();
+                if sources.is_empty() || sources.iter().any(|(src, trig)|
+                        self.allowed(item, src, trig, &alive)) {
+                    alive.insert(item.clone());
+                }
+            }
+            if alive.len() == before { break; }
+        }
+        let copies: HashMap<_, _> = alive.iter()
+            .map(|item| (item.clone(), Rc::new(item.unsourced())))
+            .collect();
+        for (item, copy) in &copies {
+            for (src, trig) in item.source().iter() {
+                if !self.allowed(item, src, trig, &alive) { continue; }
+                let trig = match *trig {
+                    Trigger::Complete(ref child) =>
+                        Trigger::Complete(copies[child].clone()),
+                    Trigger::Scan(ref token) => Trigger::Scan(token.clone()),
+                };
+                copy.add_source(copies[src].clone(), trig);
+            }
+        }
+        roots.iter().filter_map(|root| copies.get(root).cloned()).collect()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::{Grammar, GrammarBuilder};
+    use parser::{EarleyParser, Error};
+    use trees::EarleyForest;
+
+    fn arith() -> GrammarBuilder {
+        // E -> E + E | E - E | E * E | E ^ E | E == E | n
+        GrammarBuilder::default()
+          .nonterm("E")
+          .terminal("n", |n| n.chars().all(|c| c.is_ascii_digit()))
+          .terminal("+", |n| n == "+")
+          .terminal("-", |n| n == "-")
+          .terminal("*", |n| n == "*")
+          .terminal("^", |n| n == "^")
+          .terminal("==", |n| n == "==")
+          .rule("E", &["E", "+", "E"])
+          .rule("E", &["E", "-", "E"])
+          .rule("E", &["E", "*", "E"])
+          .rule("E", &["E", "^", "E"])
+          .rule("E", &["E", "==", "E"])
+          .rule("E", &["n"])
+    }
+
+    fn trees(grammar: Grammar, input: &str) -> Result<Vec<String>, Error> {
+        let mut ev = EarleyForest::new(|_, tok| tok.to_string());
+        ev.action("E -> n", |mut n| n.swap_remove(0));
+        for op in &["+", "-", "*", "^", "=="] {
+            ev.action(format!("E -> E {} E", op).as_str(),
+                      |n| format!("({} {} {})", n[0], n[1], n[2]));
+        }
+        let ptrees = EarleyParser::new(grammar)
+            .parse(input.split_whitespace())?;
+        ev.eval_all(&ptrees)
+    }
+
+    #[test]
+    fn priority_assoc() {
+        let grammar = arith()
+            .left_assoc(&["E -> E + E", "E -> E - E"])
+            .right_assoc(&["E -> E ^ E"])
+            .non_assoc(&["E -> E == E"])
+            .priority(&["E -> E ^ E"], &["E -> E * E"])
+            .priority(&["E -> E * E"], &["E -> E + E", "E -> E - E"])
+            .priority(&["E -> E + E", "E -> E - E"], &["E -> E == E"])
+            .into_grammar("E")
+            .expect("Bad grammar");
+        assert_eq!(trees(grammar.clone(), "1 + 2 * 3 ^ 4 ^ 5 - 6").unwrap(),
+                   vec!["((1 + (2 * (3 ^ (4 ^ 5)))) - 6)"]);
+        // priorities are transitive
+        assert_eq!(trees(grammar.clone(), "1 ^ 2 == 3").unwrap(),
+                   vec!["((1 ^ 2) == 3)"]);
+        assert_eq!(trees(grammar.clone(), "1 == 2 == 3").unwrap_err(),
+                   Error::Filtered("E".to_string()));
+        // without filters every tree is there
+        let grammar = arith().into_grammar("E").unwrap();
+        assert_eq!(trees(grammar, "1 + 2 * 3").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn reject_prefer() {
+        // S -> Id Id | if Id ; Id -> w | if
+        let builder = || GrammarBuilder::default()
+          .nonterm("S")
+          .nonterm("Id")
+          .terminal("w", |n| n.chars().all(|c| c.is_ascii_lowercase()))
+          .terminal("if", |n| n == "if")
+          .rule("S", &["Id", "Id"])
+          .rule("S", &["if", "Id"])
+          .rule("Id", &["w"])
+          .rule("Id", &["if"]);
+        let count = |grammar: Grammar, input: &str| {
+            let ptrees = EarleyParser::new(grammar)
+                .parse(input.split_whitespace()).unwrap();
+            EarleyForest::new(|_, _| ()).num_trees(&ptrees).unwrap()
+        };
+        let grammar = builder().into_grammar("S").unwrap();
+        assert_eq!(count(grammar, "if x"), 3);
+        // 'if' is a keyword, it can't be an Id
+        let grammar = builder().reject("Id -> if").into_grammar("S").unwrap();
+        assert_eq!(count(grammar.clone(), "if x"), 1);
+        assert_eq!(count(grammar, "x y"), 1);
+        let grammar = builder().prefer("S -> if Id").into_grammar("S").unwrap();
+        assert_eq!(count(grammar, "if x"), 1);
+        assert_eq!(builder().prefer("S -> x").into_grammar("S").unwrap_err(),
+                   Error::MissingRule("S -> x".to_string()));
+    }
+
+    #[test]
+    fn reject_leo_completion() {
+        // S -> a T | c ; T -> a S | b
+        // Completions along the right recursion are skipped by Leo's
+        // optimization, they're only rebuilt in the forest
+        let grammar = GrammarBuilder::default()
+          .nonterm("S")
+          .nonterm("T")
+          .terminal("a", |n| n == "a")
+          .terminal("b", |n| n == "b")
+          .terminal("c", |n| n == "c")
+          .rule("S", &["a", "T"])
+          .rule("S", &["c"])
+          .rule("T", &["a", "S"])
+          .rule("T", &["b"])
+          .reject("T -> a S")
+          .into_grammar("S")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar);
+        for input in &["a a c", "a a a a a b"] {
+            assert_eq!(p.parse(input.split_whitespace()).unwrap_err(),
+                       Error::Filtered("S".to_string()));
+        }
+        assert!(p.parse("a b".split_whitespace()).is_ok());
+    }
+}
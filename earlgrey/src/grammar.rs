@@ -1,38 +1,59 @@
 #![deny(warnings)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::{fmt, hash};
-use std::rc::Rc;
+use std::sync::Arc;
+use filters::{Assoc, Filter, Filters};
 use parser::Error;
+use regex::Regex;
+use templates::Template;
 
 
-pub enum Symbol {
+// Grammars are generic over the type of tokens they parse, String by default.
+// Predicates are Send + Sync so grammars can be shared across threads.
+pub enum Symbol<T = String> {
     NonTerm(String),
-    Terminal(String, Box<Fn(&str)->bool>),  // predicate that matches Terminal
+    Terminal(String, Box<Fn(&T)->bool + Send + Sync>),  // matches Terminal
 }
 
-#[derive(PartialEq,Hash)]
-pub struct Rule {
+// Position of a rule in Grammar::rules, returned when adding the rule
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub struct RuleId(pub usize);
+
+pub struct Rule<T = String> {
+    pub id: RuleId,
     pub head: String,
-    pub spec: Vec<Rc<Symbol>>,
+    pub spec: Vec<Arc<Symbol<T>>>,
+    // relative likelihood of the rule, a tree's weight is the product of the
+    // weights of its rules (eg: probabilities of each rule given its head)
+    pub weight: f64,
 }
 
-#[derive(Clone,Debug)]
-pub struct Grammar {
+#[derive(Debug)]
+pub struct Grammar<T = String> {
     pub start: String,
-    pub rules: Vec<Rc<Rule>>,
+    pub rules: Vec<Arc<Rule<T>>>,
+    // non-terminals that can derive the empty string
+    pub nullable: HashSet<String>,
+    // terminals that can start each non-terminal
+    pub first: HashMap<String, BTreeSet<String>>,
+    // disambiguation applied to parse trees
+    pub filters: Filters,
+    // template instances by their generated symbol
+    pub templates: HashMap<String, Template>,
 }
 
-#[derive(Default)]
-pub struct GrammarBuilder {
-    symbols: HashMap<String, Rc<Symbol>>,
-    rules: Vec<Rc<Rule>>,
+pub struct GrammarBuilder<T = String> {
+    symbols: HashMap<String, Arc<Symbol<T>>>,
+    rules: Vec<Arc<Rule<T>>>,
+    filters: Vec<Filter>,
+    templates: HashMap<Template, String>,
     error: Option<Error>,
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
-impl Symbol {
+impl<T> Symbol<T> {
     pub fn name(&self) -> &str {
         match *self {
             Symbol::NonTerm(ref name) => name,
@@ -43,7 +64,7 @@ impl Symbol {
 
 // Symbol implements Hash + PartialEq so they can be uniq'd in HashSets
 // Symbols are deduped by name ONLY
-impl hash::Hash for Symbol {
+impl<T> hash::Hash for Symbol<T> {
     fn hash<H: hash::Hasher>(&self, state: &mut H) {
         match *self {
             Symbol::NonTerm(ref name) => name.hash(state),
@@ -52,8 +73,8 @@ impl hash::Hash for Symbol {
     }
 }
 
-impl PartialEq for Symbol {
-    fn eq(&self, other: &Symbol) -> bool {
+impl<T> PartialEq for Symbol<T> {
+    fn eq(&self, other: &Symbol<T>) -> bool {
         match (self, other) {
             (&Symbol::NonTerm(ref a), &Symbol::NonTerm(ref b)) => a == b,
             (&Symbol::Terminal(ref a, _), &Symbol::Terminal(ref b, _)) => a == b,
@@ -64,8 +85,24 @@ impl PartialEq for Symbol {
 
 ///////////////////////////////////////////////////////////////////////////////
 
-impl Rule {
-    pub fn symbol_at(&self, idx: usize) -> Option<&Rc<Symbol>> {
+// Ids and weights are left out of the hash, there's only one rule per
+// head + spec
+impl<T> hash::Hash for Rule<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.head.hash(state);
+        self.spec.hash(state);
+    }
+}
+
+// Rules with the same head and symbols are the same rule, see Hash
+impl<T> PartialEq for Rule<T> {
+    fn eq(&self, other: &Rule<T>) -> bool {
+        self.head == other.head && self.spec == other.spec
+    }
+}
+
+impl<T> Rule<T> {
+    pub fn symbol_at(&self, idx: usize) -> Option<&Arc<Symbol<T>>> {
         self.spec.get(idx)
     }
 
@@ -75,7 +112,7 @@ impl Rule {
     }
 }
 
-impl fmt::Debug for Rule {
+impl<T> fmt::Debug for Rule<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.to_string())
     }
@@ -83,8 +120,22 @@ impl fmt::Debug for Rule {
 
 ///////////////////////////////////////////////////////////////////////////////
 
-impl Grammar {
-    pub fn rules_for(&self, head: &str) -> Vec<Rc<Rule>> {
+// Symbols and rules are shared, cloning doesn't need to clone tokens
+impl<T> Clone for Grammar<T> {
+    fn clone(&self) -> Self {
+        Grammar{start: self.start.clone(), rules: self.rules.clone(),
+                nullable: self.nullable.clone(), first: self.first.clone(),
+                filters: self.filters.clone(),
+                templates: self.templates.clone()}
+    }
+}
+
+impl<T> Grammar<T> {
+    pub fn rule(&self, id: RuleId) -> Option<&Arc<Rule<T>>> {
+        self.rules.get(id.0)
+    }
+
+    pub fn rules_for(&self, head: &str) -> Vec<Arc<Rule<T>>> {
         self.rules.iter()
             .filter(|r| r.head == head)
             .cloned()
@@ -94,15 +145,121 @@ impl Grammar {
     pub fn str_rules(&self) -> Vec<String> {
         self.rules.iter().map(|r| r.to_string()).collect()
     }
+
+    pub fn is_nullable(&self, symbol: &str) -> bool {
+        self.nullable.contains(symbol)
+    }
+
+    // A symbol is nullable if it has a rule made only of nullable symbols.
+    // Keep marking heads until no new nullable symbols show up.
+    fn nullable_symbols(rules: &[Arc<Rule<T>>]) -> HashSet<String> {
+        let mut nullable = HashSet::new();
+        loop {
+            let new: Vec<_> = rules.iter()
+                .filter(|r| !nullable.contains(&r.head))
+                .filter(|r| r.spec.iter().all(|s| match **s {
+                    Symbol::NonTerm(ref name) => nullable.contains(name),
+                    Symbol::Terminal(_, _) => false,
+                }))
+                .map(|r| r.head.clone())
+                .collect();
+            if new.is_empty() { return nullable; }
+            nullable.extend(new);
+        }
+    }
+
+    // Terminals that can start a sequence of symbols, nullable
+    // non-terminals let the symbols after them start it too.
+    pub fn first_of(&self, spec: &[Arc<Symbol<T>>]) -> BTreeSet<String> {
+        let mut first = BTreeSet::new();
+        for symbol in spec {
+            match **symbol {
+                Symbol::Terminal(ref name, _) => {
+                    first.insert(name.clone());
+                    return first;
+                },
+                Symbol::NonTerm(ref name) => {
+                    if let Some(names) = self.first.get(name) {
+                        first.extend(names.iter().cloned());
+                    }
+                    if !self.is_nullable(name) { return first; }
+                },
+            }
+        }
+        first
+    }
+
+    // Check if a sequence of symbols may start with any of 'terminals'.
+    // Nullable sequences can be skipped so they may start with anything.
+    pub fn starts_with(&self, spec: &[Arc<Symbol<T>>],
+                       terminals: &HashSet<String>) -> bool {
+        for symbol in spec {
+            match **symbol {
+                Symbol::Terminal(ref name, _) => return terminals.contains(name),
+                Symbol::NonTerm(ref name) => {
+                    if let Some(first) = self.first.get(name) {
+                        if terminals.iter().any(|t| first.contains(t)) {
+                            return true;
+                        }
+                    }
+                    if !self.is_nullable(name) { return false; }
+                },
+            }
+        }
+        true
+    }
+
+    // Same fixpoint as nullable symbols: add the FIRST set of each rule
+    // to its head until no set grows.
+    fn first_sets(rules: &[Arc<Rule<T>>], nullable: &HashSet<String>)
+            -> HashMap<String, BTreeSet<String>> {
+        let mut first: HashMap<String, BTreeSet<String>> = HashMap::new();
+        loop {
+            let mut changed = false;
+            for rule in rules {
+                let mut new = BTreeSet::new();
+                for symbol in &rule.spec {
+                    match **symbol {
+                        Symbol::Terminal(ref name, _) => {
+                            new.insert(name.clone());
+                            break;
+                        },
+                        Symbol::NonTerm(ref name) => {
+                            if let Some(names) = first.get(name) {
+                                new.extend(names.iter().cloned());
+                            }
+                            if !nullable.contains(name) { break; }
+                        },
+                    }
+                }
+                let names = first.entry(rule.head.clone()).or_default();
+                let before = names.len();
+                names.extend(new);
+                changed |= names.len() != before;
+            }
+            if !changed { return first; }
+        }
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
-impl GrammarBuilder {
-    fn add_symbol(&mut self, symbol: Symbol, ignoredup: bool) {
+impl Default for GrammarBuilder {
+    fn default() -> Self { GrammarBuilder::typed() }
+}
+
+impl<T> GrammarBuilder<T> {
+    // Builder for grammars over tokens of type T, terminals match &T
+    pub fn typed() -> Self {
+        GrammarBuilder{symbols: HashMap::new(), rules: Vec::new(),
+                       filters: Vec::new(), templates: HashMap::new(),
+                       error: None}
+    }
+
+    fn add_symbol(&mut self, symbol: Symbol<T>, ignoredup: bool) {
         // NOTE: we check existence to avoid new symbols stomping on pluged ones
         if !self.symbols.contains_key(symbol.name()) {
-            self.symbols.insert(symbol.name().to_string(), Rc::new(symbol));
+            self.symbols.insert(symbol.name().to_string(), Arc::new(symbol));
         } else if !ignoredup {
             self.error =
                 Some(Error::DuplicateSym(symbol.name().to_string()));
@@ -119,41 +276,78 @@ impl GrammarBuilder {
     }
 
     pub fn add_terminal<S, TM>(&mut self, nt: S, tm: TM, ignoredup: bool)
-            where S: Into<String>, TM: 'static + Fn(&str)->bool {
+            where S: Into<String>, TM: 'static + Send + Sync + Fn(&T)->bool {
         self.add_symbol(Symbol::Terminal(nt.into(), Box::new(tm)), ignoredup);
     }
 
     pub fn terminal<S, TM>(mut self, nt: S, tm: TM) -> Self
-            where S: Into<String>, TM: 'static + Fn(&str)->bool {
+            where S: Into<String>, TM: 'static + Send + Sync + Fn(&T)->bool {
         self.add_symbol(Symbol::Terminal(nt.into(), Box::new(tm)), false);
         self
     }
 
-    pub fn add_rule<H, S>(&mut self, head: H, spec: &[S])
+    // Terminal matching tokens that match 'pattern' whole, see Regex
+    pub fn add_terminal_re<S>(&mut self, nt: S, pattern: &str, ignoredup: bool)
+            where S: Into<String>, T: AsRef<str> {
+        match Regex::new(pattern) {
+            Ok(re) => self.add_terminal(nt, move |t: &T| re.is_match(t.as_ref()),
+                                        ignoredup),
+            // added anyway (matching nothing) so rules using it don't
+            // replace the error with a missing symbol
+            Err(e) => {
+                self.add_terminal(nt, |_: &T| false, ignoredup);
+                self.error = Some(e);
+            },
+        }
+    }
+
+    pub fn terminal_re<S>(mut self, nt: S, pattern: &str) -> Self
+            where S: Into<String>, T: AsRef<str> {
+        self.add_terminal_re(nt, pattern, false);
+        self
+    }
+
+    // Returns the id of the rule to register actions for it. Ids of rules
+    // that failed to be added are meaningless, into_grammar will fail.
+    pub fn add_rule<H, S>(&mut self, head: H, spec: &[S]) -> RuleId
             where H: Into<String>, S: AsRef<str> {
+        self.add_weighted_rule(head, spec, 1.0)
+    }
+
+    pub fn add_weighted_rule<H, S>(&mut self, head: H, spec: &[S], weight: f64)
+            -> RuleId where H: Into<String>, S: AsRef<str> {
+        let id = RuleId(self.rules.len());
         // check for missing symbols first
         if let Some(s) = spec.iter()
                 .find(|s| !self.symbols.contains_key(s.as_ref())) {
             self.error = Some(Error::MissingSym(s.as_ref().to_string()));
-            return;
+            return id;
         }
         let head = head.into();
         if !self.symbols.contains_key(&head) {
             self.error = Some(Error::MissingSym(head));
-            return;
+            return id;
         }
         let rule = Rule{
+            id,
             head,
             spec: spec.into_iter()
-                    .map(|s| self.symbols[s.as_ref()].clone()).collect()
+                    .map(|s| self.symbols[s.as_ref()].clone()).collect(),
+            weight,
         };
-        // check for duplicate rules
         let rulestr = rule.to_string();
-        if self.rules.iter().any(|r| r.to_string() == rulestr) {
+        if !(weight > 0.0 && weight.is_finite()) {
+            self.error = Some(Error::InvalidWeight(rulestr));
+            return id;
+        }
+        // check for duplicate rules
+        if let Some(dup) = self.rules.iter()
+                .find(|r| r.to_string() == rulestr) {
             self.error = Some(Error::DuplicateRule(rulestr));
-            return;
+            return dup.id;
         }
-        self.rules.push(Rc::new(rule));
+        self.rules.push(Arc::new(rule));
+        id
     }
 
     pub fn rule<H, S>(mut self, head: H, spec: &[S]) -> Self
@@ -162,7 +356,63 @@ impl GrammarBuilder {
         self
     }
 
-    pub fn into_grammar<S>(self, start: S) -> Result<Grammar, Error>
+    pub fn weighted_rule<H, S>(mut self, head: H, spec: &[S], weight: f64)
+            -> Self where H: Into<String>, S: AsRef<str> {
+        self.add_weighted_rule(head, spec, weight);
+        self
+    }
+
+    // Filters are checked against the rules on into_grammar
+    pub fn add_filter(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    // Rules of 'higher' bind tighter than rules of 'lower': trees where a
+    // 'lower' rule is the leftmost or rightmost child of a 'higher' one are
+    // dropped (eg: "E -> E * E" over "E -> E + E"). Priorities are transitive.
+    pub fn priority<S: AsRef<str>>(mut self, higher: &[S], lower: &[S])
+            -> Self {
+        let names = |rules: &[S]| rules.iter()
+            .map(|r| r.as_ref().to_string()).collect();
+        self.add_filter(Filter::Priority(names(higher), names(lower)));
+        self
+    }
+
+    fn assoc<S: AsRef<str>>(mut self, assoc: Assoc, rules: &[S]) -> Self {
+        let rules = rules.iter().map(|r| r.as_ref().to_string()).collect();
+        self.add_filter(Filter::Assoc(assoc, rules));
+        self
+    }
+
+    // Rules of the group nest on their left only (eg: 1 - 2 + 3 is
+    // (1 - 2) + 3)
+    pub fn left_assoc<S: AsRef<str>>(self, rules: &[S]) -> Self {
+        self.assoc(Assoc::Left, rules)
+    }
+
+    pub fn right_assoc<S: AsRef<str>>(self, rules: &[S]) -> Self {
+        self.assoc(Assoc::Right, rules)
+    }
+
+    // Rules of the group can't nest at all (eg: a == b == c fails)
+    pub fn non_assoc<S: AsRef<str>>(self, rules: &[S]) -> Self {
+        self.assoc(Assoc::Non, rules)
+    }
+
+    // Spans the rule matches can't be derived by its head (eg: a keyword
+    // rule "Id -> if" keeps 'if' from being an identifier)
+    pub fn reject<S: Into<String>>(mut self, rule: S) -> Self {
+        self.add_filter(Filter::Reject(rule.into()));
+        self
+    }
+
+    // Spans the rule matches can't be derived by other rules of its head
+    pub fn prefer<S: Into<String>>(mut self, rule: S) -> Self {
+        self.add_filter(Filter::Prefer(rule.into()));
+        self
+    }
+
+    pub fn into_grammar<S>(self, start: S) -> Result<Grammar<T>, Error>
             where S: Into<String> {
         if let Some(e) = self.error {
             return Err(e);
@@ -171,20 +421,68 @@ impl GrammarBuilder {
         if !self.symbols.contains_key(&start) {
             return Err(Error::MissingSym(start));
         }
-        Ok(Grammar{start, rules: self.rules})
+        let nullable = Grammar::nullable_symbols(&self.rules);
+        let first = Grammar::first_sets(&self.rules, &nullable);
+        let filters = Filters::new(&self.filters, &self.rules)?;
+        let templates = self.templates.into_iter()
+            .map(|(template, name)| (name, template))
+            .collect();
+        Ok(Grammar{start, rules: self.rules, nullable, first, filters,
+                   templates})
     }
 
     // used to generate symbols programatically
     pub fn unique_symbol_name(&self) -> String {
         format!("<Uniq-{}>", self.symbols.len())
     }
+
+    // Generated non-terminal expanding 'template' into ordinary rules. The
+    // same template with the same arguments is the same symbol.
+    pub fn add_template(&mut self, template: Template) -> String {
+        if let Some(name) = self.templates.get(&template) {
+            return name.clone();
+        }
+        // instances can build on others, add those first
+        let sep_by1 = match template {
+            Template::SepBy(ref x, ref sep) =>
+                Some(self.add_template(Template::SepBy1(x.clone(), sep.clone()))),
+            _ => None,
+        };
+        let name = self.unique_symbol_name();
+        self.add_nonterm(name.as_str(), false);
+        let (n, empty): (&str, &[&str]) = (&name, &[]);
+        match template {
+            Template::Many(ref x) => {
+                self.add_rule(n, empty);
+                self.add_rule(n, &[n, x.as_str()]);
+            },
+            Template::Many1(ref x) => {
+                self.add_rule(n, &[x]);
+                self.add_rule(n, &[n, x.as_str()]);
+            },
+            Template::Optional(ref x) => {
+                self.add_rule(n, empty);
+                self.add_rule(n, &[x]);
+            },
+            Template::SepBy(_, _) => {
+                self.add_rule(n, empty);
+                self.add_rule(n, &[sep_by1.expect("BUG: no SepBy1")]);
+            },
+            Template::SepBy1(ref x, ref sep) => {
+                self.add_rule(n, &[x]);
+                self.add_rule(n, &[n, sep.as_str(), x.as_str()]);
+            },
+        }
+        self.templates.insert(template, name.clone());
+        name
+    }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
 mod tests {
-    use super::{GrammarBuilder, Error};
+    use super::{GrammarBuilder, RuleId, Error};
 
     #[test]
     fn build_grammar() {
@@ -220,6 +518,107 @@ mod tests {
             .into_grammar("Sum");
         assert_eq!(g.unwrap_err(),
                    Error::DuplicateRule("Sum -> Sum + Num".to_string()));
+        // weights don't make rules different
+        let g = GrammarBuilder::default()
+            .nonterm("Sum")
+            .terminal("Num", |n| n.chars().all(|c| "123".contains(c)))
+            .rule("Sum", &["Num"])
+            .weighted_rule("Sum", &["Num"], 0.5)
+            .into_grammar("Sum");
+        assert_eq!(g.unwrap_err(),
+                   Error::DuplicateRule("Sum -> Num".to_string()));
+    }
+
+    #[test]
+    fn rule_ids() {
+        let mut gb = GrammarBuilder::default()
+            .nonterm("Sum")
+            .terminal("Num", |n| n.chars().all(|c| "123".contains(c)))
+            .terminal("+", |n| n == "+");
+        let sum = gb.add_rule("Sum", &["Sum", "+", "Num"]);
+        let num = gb.add_rule("Sum", &["Num"]);
+        assert_eq!((sum, num), (RuleId(0), RuleId(1)));
+        let g = gb.into_grammar("Sum").unwrap();
+        assert_eq!(g.rule(num).unwrap().to_string(), "Sum -> Num");
+        assert!(g.rule(RuleId(2)).is_none());
+    }
+
+    #[test]
+    fn weighted_rules() {
+        let g = GrammarBuilder::default()
+            .nonterm("Sum")
+            .terminal("Num", |n| n.chars().all(|c| "123".contains(c)))
+            .terminal("+", |n| n == "+")
+            .weighted_rule("Sum", &["Sum", "+", "Num"], 0.3)
+            .rule("Sum", &["Num"])
+            .into_grammar("Sum")
+            .unwrap();
+        let weights: Vec<_> = g.rules.iter().map(|r| r.weight).collect();
+        assert_eq!(weights, vec![0.3, 1.0]);
+
+        let g = GrammarBuilder::default()
+            .nonterm("Sum")
+            .terminal("Num", |n| n.chars().all(|c| "123".contains(c)))
+            .weighted_rule("Sum", &["Num"], 0.0)
+            .into_grammar("Sum");
+        assert_eq!(g.unwrap_err(),
+                   Error::InvalidWeight("Sum -> Num".to_string()));
+    }
+
+    #[test]
+    fn nullable_symbols() {
+        // A -> B C | x ; B -> <e> ; C -> B B ; D -> B x
+        let g = GrammarBuilder::default()
+            .nonterm("A")
+            .nonterm("B")
+            .nonterm("C")
+            .nonterm("D")
+            .terminal("x", |n| n == "x")
+            .rule("A", &["B", "C"])
+            .rule("A", &["x"])
+            .rule::<_, &str>("B", &[])
+            .rule("C", &["B", "B"])
+            .rule("D", &["B", "x"])
+            .into_grammar("A")
+            .unwrap();
+        assert!(g.is_nullable("A"));
+        assert!(g.is_nullable("B"));
+        assert!(g.is_nullable("C"));
+        assert!(!g.is_nullable("D"));
+        assert!(!g.is_nullable("x"));
+    }
+
+    #[test]
+    fn first_sets() {
+        // A -> B C | x ; B -> <e> | b ; C -> c | A ; D -> B d
+        let g = GrammarBuilder::default()
+            .nonterm("A")
+            .nonterm("B")
+            .nonterm("C")
+            .nonterm("D")
+            .terminal("x", |n| n == "x")
+            .terminal("b", |n| n == "b")
+            .terminal("c", |n| n == "c")
+            .terminal("d", |n| n == "d")
+            .rule("A", &["B", "C"])
+            .rule("A", &["x"])
+            .rule::<_, &str>("B", &[])
+            .rule("B", &["b"])
+            .rule("C", &["c"])
+            .rule("C", &["A"])
+            .rule("D", &["B", "d"])
+            .into_grammar("A")
+            .unwrap();
+        let first = |sym: &str| g.first[sym].iter().cloned().collect::<Vec<_>>();
+        assert_eq!(first("A"), vec!["b", "c", "x"]);
+        assert_eq!(first("B"), vec!["b"]);
+        assert_eq!(first("C"), vec!["b", "c", "x"]);
+        assert_eq!(first("D"), vec!["b", "d"]);
+        let d = &g.rules_for("D")[0];
+        assert!(g.starts_with(&d.spec, &["d".to_string()].iter().cloned().collect()));
+        assert!(!g.starts_with(&d.spec, &["x".to_string()].iter().cloned().collect()));
+        assert_eq!(g.first_of(&d.spec[1..]).into_iter().collect::<Vec<_>>(),
+                   vec!["d"]);
     }
 
     #[test]
@@ -3,29 +3,81 @@
 use grammar::{Symbol, Rule};
 use std::{cell, fmt, hash, iter};
 use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 use std::rc::Rc;
+use std::sync::Arc;
 
 
-#[derive(PartialEq,Eq,Hash)]
-pub enum Trigger {
-    Complete(Rc<Item>),
-    Scan(String),
+pub enum Trigger<T> {
+    Complete(Rc<Item<T>>),
+    Scan(Rc<T>),
 }
 
 // Earley items
-pub struct Item {
-    pub rule: Rc<Rule>,  // LR0item (dotted rule)
+pub struct Item<T> {
+    pub rule: Arc<Rule<T>>,  // LR0item (dotted rule)
     pub dot: usize,      // dot position within the rule
     pub start: usize,    // stream position where item starts
     pub end: usize,      // stream position where item ends
     // backpointers leading to this item: (source-item, Scan/Complete)
-    bp: cell::RefCell<HashSet<(Rc<Item>, Trigger)>>
+    bp: cell::RefCell<BackPointers<T>>,
+    // completions skipped by Leo's optimization: (path, completed-item)
+    // they're rebuilt into regular backpointers when walking the source
+    leo: cell::RefCell<HashSet<(Rc<LeoLink<T>>, Rc<Item<T>>)>>,
+    // items rebuilt along those paths, every completion of an equal item
+    // merges its backpointers into the same one (ie: ambiguity is kept)
+    rebuilt: cell::RefCell<HashSet<Rc<Item<T>>>>,
+}
+
+pub type BackPointers<T> = HashSet<(Rc<Item<T>>, Trigger<T>)>;
+
+// Leo's deterministic reduction path (transitive items). 'source' is the
+// only item in its StateSet waiting on a symbol, and that symbol is the last
+// of its rule. Completing the symbol can only complete 'source', and that in
+// turn goes on 'above' up to the top of the path. Linking them avoids adding
+// every item along the path on each completion (eg: right recursion).
+pub struct LeoLink<T> {
+    pub source: Rc<Item<T>>,
+    pub above: Option<Rc<LeoLink<T>>>,
+}
+
+// Each scanned token is shared by all its Scan triggers, so tokens are
+// compared by address. Tokens don't need to be Hash + Eq themselves.
+impl<T> hash::Hash for Trigger<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        match *self {
+            Trigger::Complete(ref item) => item.hash(state),
+            Trigger::Scan(ref token) => (&**token as *const T).hash(state),
+        }
+    }
+}
+
+impl<T> PartialEq for Trigger<T> {
+    fn eq(&self, other: &Trigger<T>) -> bool {
+        match (self, other) {
+            (Trigger::Complete(a), Trigger::Complete(b)) => a == b,
+            (Trigger::Scan(a), Trigger::Scan(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl<T> Eq for Trigger<T> {}
+
+impl<T> Clone for Trigger<T> {
+    fn clone(&self) -> Self {
+        match *self {
+            Trigger::Complete(ref item) => Trigger::Complete(item.clone()),
+            Trigger::Scan(ref token) => Trigger::Scan(token.clone()),
+        }
+    }
 }
 
 
 // Items are deduped only by rule, dot, start, end (ie: not bp)
 // This is needed to insert into StateSet merging back-pointers
-impl hash::Hash for Item {
+impl<T> hash::Hash for Item<T> {
     fn hash<H: hash::Hasher>(&self, state: &mut H) {
         self.rule.hash(state);
         self.dot.hash(state);
@@ -34,8 +86,8 @@ impl hash::Hash for Item {
     }
 }
 
-impl PartialEq for Item {
-    fn eq(&self, other: &Item) -> bool {
+impl<T> PartialEq for Item<T> {
+    fn eq(&self, other: &Item<T>) -> bool {
         self.rule == other.rule &&
         self.dot == other.dot &&
         self.start == other.start &&
@@ -43,9 +95,20 @@ impl PartialEq for Item {
     }
 }
 
-impl Eq for Item {}
+impl<T> Eq for Item<T> {}
+
+// There's at most one link per (StateSet, symbol), so source identifies them
+impl<T> hash::Hash for LeoLink<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) { self.source.hash(state) }
+}
+
+impl<T> PartialEq for LeoLink<T> {
+    fn eq(&self, other: &LeoLink<T>) -> bool { self.source == other.source }
+}
+
+impl<T> Eq for LeoLink<T> {}
 
-impl fmt::Debug for Item {
+impl<T> fmt::Debug for Item<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let pre = self.rule.spec.iter().take(self.dot)
             .map(|s| s.name()).collect::<Vec<_>>().join(" ");
@@ -53,58 +116,120 @@ impl fmt::Debug for Item {
             .map(|s| s.name()).collect::<Vec<_>>().join(" ");
         write!(f, "({} - {}) {} -> {} \u{00b7} {} #bp: {}",
                self.start, self.end, self.rule.head, pre, post,
-               self.bp.borrow().len())
+               self.bp.borrow().len() + self.leo.borrow().len())
     }
 }
 
-impl Item {
+impl<T> Item<T> {
     pub fn complete(&self) -> bool { self.dot >= self.rule.spec.len() }
 
-    pub fn next_symbol(&self) -> Option<&Symbol> {
+    pub fn next_symbol(&self) -> Option<&Symbol<T>> {
         self.rule.symbol_at(self.dot).map(|s| &**s)
     }
 
     // only ever borrowed non-mutable ref returned for public consumption
-    pub fn source(&self) -> cell::Ref<HashSet<(Rc<Item>, Trigger)>> {
+    pub fn source(&self) -> cell::Ref<'_, BackPointers<T>> {
+        // rebuild items along Leo paths, each completing the one above
+        let mut rebuilt = self.rebuilt.borrow_mut();
+        let paths: Vec<_> = self.leo.borrow_mut().drain().collect();
+        // completions in the chart (found through other paths) may be equal
+        // to rebuilt ones, their derivations are merged into the chart's item
+        rebuilt.extend(paths.iter().map(|(_, trigger)| trigger.clone()));
+        rebuilt.extend(self.bp.borrow().iter().filter_map(|(_, trigger)|
+            match trigger {
+                Trigger::Complete(item) => Some(item.clone()),
+                Trigger::Scan(_) => None,
+            }));
+        'paths: for (link, trigger) in paths {
+            let (mut link, mut completed) = (link, trigger);
+            while let Some(above) = link.above.clone() {
+                let item = Item::complete_new(&link.source, &completed,
+                                              self.end);
+                // the path above it was rebuilt (or completed) already
+                if let Some(existing) = rebuilt.get(&item) {
+                    existing.add_source(link.source.clone(),
+                                        Trigger::Complete(completed));
+                    continue 'paths;
+                }
+                completed = Rc::new(item);
+                rebuilt.insert(completed.clone());
+                link = above;
+            }
+            self.bp.borrow_mut()
+                .insert((link.source.clone(), Trigger::Complete(completed)));
+        }
         self.bp.borrow()
     }
 
     // check if other item's next-symbol matches our rule's name
-    fn can_complete(&self, other: &Rc<Item>) -> bool {
+    fn can_complete(&self, other: &Rc<Item<T>>) -> bool {
         self.complete() && match other.next_symbol() {
             Some(&Symbol::NonTerm(ref name)) => name == &self.rule.head,
             _ => false
         }
     }
 
-    // check item's next symbol is a temrinal that scans lexeme
-    pub fn can_scan(&self, lexeme: &str) -> bool {
+    // check item's next symbol is a temrinal that scans token
+    pub fn can_scan(&self, token: &T) -> bool {
         match self.next_symbol() {
-            Some(&Symbol::Terminal(_, ref f)) => f(lexeme),
+            Some(&Symbol::Terminal(_, ref f)) => f(token),
             _ => false
         }
     }
 
     // build a new Item for a prediction
-    pub fn predict_new(rule: &Rc<Rule>, start: usize) -> Item {
+    pub fn predict_new(rule: &Arc<Rule<T>>, start: usize) -> Item<T> {
         Item{rule: rule.clone(), dot: 0, start, end: start,
-             bp: cell::RefCell::new(HashSet::new())}
+             bp: cell::RefCell::new(HashSet::new()),
+             leo: cell::RefCell::new(HashSet::new()),
+             rebuilt: cell::RefCell::new(HashSet::new())}
     }
 
     // produce an Item after scanning a token
-    fn scan_new(source: &Rc<Item>, end: usize, input: String) -> Item {
+    fn scan_new(source: &Rc<Item<T>>, end: usize, input: Rc<T>) -> Item<T> {
         let mut _bp = HashSet::new();
         _bp.insert((source.clone(), Trigger::Scan(input)));
         Item{rule: source.rule.clone(), dot: source.dot+1,
-             start: source.start, end, bp: cell::RefCell::new(_bp)}
+             start: source.start, end, bp: cell::RefCell::new(_bp),
+             leo: cell::RefCell::new(HashSet::new()),
+             rebuilt: cell::RefCell::new(HashSet::new())}
     }
 
     // produce an Item by completing another one
-    fn complete_new(source: &Rc<Item>, trigger: &Rc<Item>, end: usize) -> Item {
+    fn complete_new(source: &Rc<Item<T>>, trigger: &Rc<Item<T>>, end: usize)
+            -> Item<T> {
         let mut _bp = HashSet::new();
         _bp.insert((source.clone(), Trigger::Complete(trigger.clone())));
         Item{rule: source.rule.clone(), dot: source.dot+1,
-             start: source.start, end, bp: cell::RefCell::new(_bp)}
+             start: source.start, end, bp: cell::RefCell::new(_bp),
+             leo: cell::RefCell::new(HashSet::new()),
+             rebuilt: cell::RefCell::new(HashSet::new())}
+    }
+
+    // same Item without back-pointers, they're added by add_source
+    pub fn unsourced(&self) -> Item<T> {
+        Item{rule: self.rule.clone(), dot: self.dot, start: self.start,
+             end: self.end, bp: cell::RefCell::new(HashSet::new()),
+             leo: cell::RefCell::new(HashSet::new()),
+             rebuilt: cell::RefCell::new(HashSet::new())}
+    }
+
+    pub fn add_source(&self, source: Rc<Item<T>>, trigger: Trigger<T>) {
+        self.bp.borrow_mut().insert((source, trigger));
+    }
+
+    // produce the Item at the top of a Leo path completed by 'trigger'
+    pub fn leo_new(link: &Rc<LeoLink<T>>, trigger: &Rc<Item<T>>, end: usize)
+            -> Item<T> {
+        let mut top = link;
+        while let Some(ref above) = top.above { top = above; }
+        let mut _leo = HashSet::new();
+        _leo.insert((link.clone(), trigger.clone()));
+        Item{rule: top.source.rule.clone(), dot: top.source.dot+1,
+             start: top.source.start, end,
+             bp: cell::RefCell::new(HashSet::new()),
+             leo: cell::RefCell::new(_leo),
+             rebuilt: cell::RefCell::new(HashSet::new())}
     }
 }
 
@@ -112,51 +237,97 @@ impl Item {
 ///////////////////////////////////////////////////////////////////////////////
 
 
-#[derive(Default)]
-pub struct StateSet(HashSet<Rc<Item>>);
+pub struct StateSet<T>(HashSet<Rc<Item<T>>>);
+
+impl<T> Default for StateSet<T> {
+    fn default() -> Self { StateSet(HashSet::new()) }
+}
+
+// Items are shared, tokens don't need to be Clone
+impl<T> Clone for StateSet<T> {
+    fn clone(&self) -> Self { StateSet(self.0.clone()) }
+}
 
-impl StateSet {
+// StateSets are equal when their items are, whatever order they're kept in
+impl<T> hash::Hash for StateSet<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        let items = self.0.iter().fold(0u64, |sum, item| {
+            let mut hasher = DefaultHasher::new();
+            item.hash(&mut hasher);
+            sum.wrapping_add(hasher.finish())
+        });
+        items.hash(state);
+    }
+}
+
+impl<T> PartialEq for StateSet<T> {
+    fn eq(&self, other: &StateSet<T>) -> bool { self.0 == other.0 }
+}
+
+impl<T> Eq for StateSet<T> {}
+
+impl<T> StateSet<T> {
     // Add Earley Items into the set. If the Item already exists we merge bp
     // StateSets override insertion to merge back-pointers for existing Items.
     // See implementations of Hash + PartialEq + Eq for Item excluding Item::bp
-    fn insert(&mut self, item: Item) {
+    // Returns the Item if it wasn't part of the set yet.
+    pub fn insert(&mut self, item: Item<T>) -> Option<Rc<Item<T>>> {
         if let Some(existent) = self.0.get(&item) {
             let back_pointers = item.bp.into_inner();
             existent.bp.borrow_mut().extend(back_pointers);
-            return;
+            existent.leo.borrow_mut().extend(item.leo.into_inner());
+            return None;
         }
-        self.0.insert(Rc::new(item));
+        let item = Rc::new(item);
+        self.0.insert(item.clone());
+        Some(item)
+    }
+
+    pub fn remove(&mut self, item: &Rc<Item<T>>) -> bool {
+        self.0.remove(item)
     }
 
     pub fn len(&self) -> usize { self.0.len() }
 
-    pub fn iter(&self) -> impl Iterator<Item=&Rc<Item>> { self.0.iter() }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    pub fn iter(&self) -> impl Iterator<Item=&Rc<Item<T>>> { self.0.iter() }
 
     // Produce new items by advancing the dot on items completed by 'item' trig
-    pub fn completed_at(&self, item: &Rc<Item>, at: usize) -> Vec<Item> {
+    pub fn completed_at(&self, item: &Rc<Item<T>>, at: usize) -> Vec<Item<T>> {
         self.0.iter()
             .filter(|source| item.can_complete(source))
             .map(|source| Item::complete_new(source, item, at))
             .collect()
     }
 
-    // Produce new items by advancing the dot on items that can 'scan' lexeme
-    pub fn advanced_by_scan(&self, lexeme: &str, end: usize) -> Vec<Item> {
+    // Advance 'item' over its nullable next symbol using items in this set
+    // that completed it without consuming input (ie: started 'at' the set)
+    pub fn advanced_by_null(&self, item: &Rc<Item<T>>, at: usize)
+            -> Vec<Item<T>> {
+        self.0.iter()
+            .filter(|null| null.start == at && null.can_complete(item))
+            .map(|null| Item::complete_new(item, null, at))
+            .collect()
+    }
+
+    // Produce new items by advancing the dot on items that can 'scan' token
+    pub fn advanced_by_scan(&self, token: &Rc<T>, end: usize) -> Vec<Item<T>> {
         self.0.iter()
-            .filter(|item| item.can_scan(lexeme))
-            .map(|item| Item::scan_new(item, end, lexeme.to_string()))
+            .filter(|item| item.can_scan(token))
+            .map(|item| Item::scan_new(item, end, token.clone()))
             .collect()
     }
 }
 
-impl Extend<Item> for StateSet {
-    fn extend<I: IntoIterator<Item=Item>>(&mut self, iterable: I) {
+impl<T> Extend<Item<T>> for StateSet<T> {
+    fn extend<I: IntoIterator<Item=Item<T>>>(&mut self, iterable: I) {
         for item in iterable { self.insert(item); }
     }
 }
 
-impl iter::FromIterator<Item> for StateSet {
-    fn from_iter<I: IntoIterator<Item=Item>>(iterable: I) -> Self {
+impl<T> iter::FromIterator<Item<T>> for StateSet<T> {
+    fn from_iter<I: IntoIterator<Item=Item<T>>>(iterable: I) -> Self {
         let mut ss = StateSet::default();
         ss.extend(iterable.into_iter());
         ss
@@ -164,9 +335,9 @@ impl iter::FromIterator<Item> for StateSet {
 }
 
 use std::collections::hash_set;
-impl iter::IntoIterator for StateSet {
-    type Item = Rc<Item>;
-    type IntoIter = hash_set::IntoIter<Rc<Item>>;
+impl<T> iter::IntoIterator for StateSet<T> {
+    type Item = Rc<Item<T>>;
+    type IntoIter = hash_set::IntoIter<Rc<Item<T>>>;
     fn into_iter(self) -> Self::IntoIter { self.0.into_iter() }
 }
 
@@ -177,37 +348,46 @@ impl iter::IntoIterator for StateSet {
 #[cfg(test)]
 mod tests {
     use std::rc::Rc;
+    use std::sync::Arc;
     use std::collections::HashSet;
     use std::cell::RefCell;
     use super::{Rule, Item, Symbol, StateSet, Trigger};
+    use grammar::RuleId;
 
-    fn gen_rule1() -> Rc<Rule> {
-        fn testfn(o: &str) -> bool { o.len() == 1 && "+-".contains(o) }
-        Rc::new(Rule{
+    fn gen_rule1() -> Arc<Rule> {
+        Arc::new(Rule{
+            id: RuleId(0),
             head: "S".to_string(),
             spec: vec![
-                Rc::new(Symbol::NonTerm("S".to_string())),
-                Rc::new(Symbol::Terminal("+-".to_string(), Box::new(testfn))),
-                Rc::new(Symbol::Terminal("d".to_string(), Box::new(|n|
+                Arc::new(Symbol::NonTerm("S".to_string())),
+                Arc::new(Symbol::Terminal("+-".to_string(), Box::new(|o: &String|
+                                      o.len() == 1 && "+-".contains(o.as_str())))),
+                Arc::new(Symbol::Terminal("d".to_string(), Box::new(|n: &String|
                                       n.chars().all(|c| "123".contains(c))))),
-            ]})
+            ],
+            weight: 1.0})
     }
 
-    fn gen_rule2() -> Rc<Rule> {
-        fn testfn(o: &str) -> bool { o.len() == 1 && "*/".contains(o) }
-        Rc::new(Rule{
+    fn gen_rule2() -> Arc<Rule> {
+        Arc::new(Rule{
+            id: RuleId(1),
             head: "M".to_string(),
             spec: vec![
-                Rc::new(Symbol::NonTerm("M".to_string())),
-                Rc::new(Symbol::Terminal("*/".to_string(), Box::new(testfn))),
-                Rc::new(Symbol::Terminal("d".to_string(), Box::new(|n|
+                Arc::new(Symbol::NonTerm("M".to_string())),
+                Arc::new(Symbol::Terminal("*/".to_string(), Box::new(|o: &String|
+                                      o.len() == 1 && "*/".contains(o.as_str())))),
+                Arc::new(Symbol::Terminal("d".to_string(), Box::new(|n: &String|
                                       n.chars().all(|c| "123".contains(c))))),
-            ]})
+            ],
+            weight: 1.0})
     }
 
-    fn item(rule: Rc<Rule>, dot: usize, start: usize, end: usize) -> Item {
+    fn item(rule: Arc<Rule>, dot: usize, start: usize, end: usize)
+            -> Item<String> {
         Item{rule: rule, dot: dot, start: start, end: end,
-             bp: RefCell::new(HashSet::new())}
+             bp: RefCell::new(HashSet::new()),
+             leo: RefCell::new(HashSet::new()),
+             rebuilt: RefCell::new(HashSet::new())}
     }
 
     #[test]
@@ -226,12 +406,13 @@ mod tests {
     fn scan_eq() {
         let i = Rc::new(item(gen_rule1(), 2, 0, 0));
         // i Item is doted after '/*', so it can scan a digit
-        assert!(i.can_scan("1"));
-        let i2 = Item::scan_new(&i, 1, "3".to_string());
+        assert!(i.can_scan(&"1".to_string()));
+        let token = Rc::new("3".to_string());
+        let i2 = Item::scan_new(&i, 1, token.clone());
         assert_eq!(i2, item(gen_rule1(), 3, 0, 1));
         // Assert i2 has back pointer
         assert_eq!(i2.source().len(), 1);
-        assert!(i2.source().contains(&(i, Trigger::Scan("3".to_string()))));
+        assert!(i2.source().contains(&(i, Trigger::Scan(token))));
     }
 
     #[test]
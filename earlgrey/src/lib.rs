@@ -1,14 +1,45 @@
 #![deny(warnings)]
 
 mod grammar;
-pub use grammar::{GrammarBuilder, Grammar};
+pub use grammar::{GrammarBuilder, Grammar, RuleId};
+
+mod regex;
+pub use regex::Regex;
+
+mod serialize;
+
+mod scannerless;
+
+mod analysis;
+pub use analysis::Diagnostic;
 
 mod items;
 mod parser;
-pub use parser::{EarleyParser, Error};
+pub use parser::{EarleyParser, ParseSession, ParseTrees, Span, Error};
+
+mod export;
+
+mod filters;
+pub use filters::{Filters, Filter, Assoc};
+
+mod templates;
+pub use templates::Template;
+
+mod limits;
+pub use limits::{Limits, Limit};
+
+mod stats;
+pub use stats::{Stats, Step};
+
+mod recovery;
+pub use recovery::{Recovery, Edit, Repaired};
 
 mod trees;
-pub use trees::EarleyForest;
+pub use trees::{EarleyForest, SyncEarleyForest, Closures, Local, Shared, Trees,
+                Fallible, ActionKey};
+
+mod sppf;
+pub use sppf::{Sppf, SppfNode, Packed, NodeId};
 
 #[cfg(test)]
 mod parser_test;
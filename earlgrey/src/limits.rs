@@ -0,0 +1,172 @@
+#![deny(warnings)]
+
+use parser::EarleyParser;
+use std::fmt;
+
+
+// Resource limit a parse ran into
+#[derive(Clone,Debug,PartialEq)]
+pub enum Limit {
+    // the chart grew past this many items
+    Items(usize),
+    // a single StateSet grew past this many items
+    SetItems(usize),
+    // the input is longer than this many tokens
+    Tokens(usize),
+    // the cancellation hook stopped the parse before this token
+    Cancelled(usize),
+}
+
+// Bounds on the work a parse may do, nothing is bounded by default.
+// Use them to parse untrusted input with pathological grammars or inputs.
+#[derive(Default)]
+pub struct Limits {
+    max_items: Option<usize>,
+    max_set_items: Option<usize>,
+    max_tokens: Option<usize>,
+    cancel: Option<Box<Fn(usize) -> bool + Send + Sync>>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Limit::Items(max) => write!(f, "more than {} items", max),
+            Limit::SetItems(max) =>
+                write!(f, "more than {} items in a StateSet", max),
+            Limit::Tokens(max) => write!(f, "more than {} tokens", max),
+            Limit::Cancelled(idx) => write!(f, "cancelled at token {}", idx),
+        }
+    }
+}
+
+impl Limits {
+    pub fn max_items(mut self, max: usize) -> Self {
+        self.max_items = Some(max);
+        self
+    }
+
+    pub fn max_set_items(mut self, max: usize) -> Self {
+        self.max_set_items = Some(max);
+        self
+    }
+
+    pub fn max_tokens(mut self, max: usize) -> Self {
+        self.max_tokens = Some(max);
+        self
+    }
+
+    // Called between StateSets with the number of tokens consumed so far,
+    // returning true stops the parse (eg: a deadline passed)
+    pub fn cancel_if<F>(mut self, cancel: F) -> Self
+            where F: 'static + Send + Sync + Fn(usize) -> bool {
+        self.cancel = Some(Box::new(cancel));
+        self
+    }
+
+    // Check the items of the StateSet being built and of the whole chart
+    pub fn check_items(&self, set_items: usize, items: usize)
+            -> Result<(), Limit> {
+        match (self.max_set_items, self.max_items) {
+            (Some(max), _) if set_items > max => Err(Limit::SetItems(max)),
+            (_, Some(max)) if items > max => Err(Limit::Items(max)),
+            _ => Ok(()),
+        }
+    }
+
+    // Check before pushing the token at 'idx'
+    pub fn check_token(&self, idx: usize) -> Result<(), Limit> {
+        match self.max_tokens {
+            Some(max) if idx >= max => return Err(Limit::Tokens(max)),
+            _ => (),
+        }
+        match self.cancel {
+            Some(ref cancel) if cancel(idx) => Err(Limit::Cancelled(idx)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<T> EarleyParser<T> {
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::{Grammar, GrammarBuilder};
+    use parser::{EarleyParser, Error};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use super::{Limits, Limit};
+
+    fn grammar() -> Grammar {
+        // S -> S S | b
+        GrammarBuilder::default()
+          .nonterm("S")
+          .terminal("b", |n| n == "b")
+          .rule("S", &["S", "S"])
+          .rule("S", &["b"])
+          .into_grammar("S")
+          .expect("Bad grammar")
+    }
+
+    #[test]
+    fn unlimited() {
+        let p = EarleyParser::new(grammar()).with_limits(Limits::default());
+        assert!(p.parse(vec!["b"; 30].into_iter()).is_ok());
+    }
+
+    #[test]
+    fn item_limits() {
+        let p = EarleyParser::new(grammar())
+            .with_limits(Limits::default().max_items(500));
+        assert!(p.parse(vec!["b"; 5].into_iter()).is_ok());
+        assert_eq!(p.parse(vec!["b"; 30].into_iter()).unwrap_err(),
+                   Error::LimitExceeded(Limit::Items(500)));
+
+        let p = EarleyParser::new(grammar())
+            .with_limits(Limits::default().max_set_items(20));
+        let mut session = p.session();
+        let mut pushed = 0;
+        let err = loop {
+            match session.push("b") {
+                Ok(_) => pushed += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(err, Error::LimitExceeded(Limit::SetItems(20)));
+        assert!(pushed > 1);
+        // the chart is incomplete, the session can't be used anymore
+        assert_eq!(session.push("b").unwrap_err(), err);
+        assert_eq!(session.parse_trees().unwrap_err(), err);
+        assert_eq!(err.to_string(),
+                   "Parse aborted: more than 20 items in a StateSet");
+    }
+
+    #[test]
+    fn token_limits() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let p = EarleyParser::new(grammar())
+            .with_limits(Limits::default()
+                         .max_tokens(10)
+                         .cancel_if(move |idx| {
+                             counter.fetch_add(1, Ordering::SeqCst);
+                             idx == 8
+                         }));
+        assert!(p.parse(vec!["b"; 8].into_iter()).is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 8);
+        assert_eq!(p.parse(vec!["b"; 9].into_iter()).unwrap_err(),
+                   Error::LimitExceeded(Limit::Cancelled(8)));
+        let p = EarleyParser::new(grammar())
+            .with_limits(Limits::default().max_tokens(10));
+        assert_eq!(p.parse(vec!["b"; 11].into_iter()).unwrap_err(),
+                   Error::LimitExceeded(Limit::Tokens(10)));
+    }
+}
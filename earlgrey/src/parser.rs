@@ -1,112 +1,577 @@
 #![deny(warnings)]
 
+use export::Graph;
 use grammar::{Symbol, Grammar};
-use items::{Item, StateSet};
+use items::{Item, LeoLink, StateSet};
+use limits::{Limits, Limit};
+use stats::{Stats, Step};
+use recovery::Recovery;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::rc::Rc;
+use std::sync::Arc;
 
 
 #[derive(Debug,PartialEq)]
 pub enum Error {
-    ParseError,
+    // Parsing stopped at token 'index': 'lexeme' (the token as displayed)
+    // couldn't be scanned (None if input ended early) while only 'expected'
+    // terminals were acceptable
+    ParseError {
+        index: usize,
+        lexeme: Option<String>,
+        expected: Vec<String>,
+    },
     MissingAction(String),
     MissingSym(String),
+    // A disambiguation filter names a rule the grammar doesn't have
+    MissingRule(String),
     DuplicateSym(String),
     DuplicateRule(String),
+    InvalidWeight(String),
+    // Semantic actions rejected every tree of the symbol
+    Rejected(String),
+    // Disambiguation filters removed every tree of the symbol
+    Filtered(String),
+    // Bytes given to GrammarBuilder::load aren't a serialized grammar
+    InvalidData(String),
+    // Pattern of GrammarBuilder::terminal_re and why it can't be compiled
+    InvalidRegex(String),
+    // Parsing stopped, it would take more resources than allowed
+    LimitExceeded(Limit),
 }
 
-pub struct EarleyParser {
-    pub g: Grammar,
+pub struct EarleyParser<T = String> {
+    pub g: Grammar<T>,
     debug: bool,
+    pub recovery: Option<Recovery<T>>,
+    pub limits: Limits,
+    // skip predictions that can't start with the next token
+    lookahead: bool,
+    // complete right recursions through Leo paths
+    leo: bool,
+    terminals: Vec<Arc<Symbol<T>>>,
 }
 
 #[derive(Debug)]
-pub struct ParseTrees(pub Vec<Rc<Item>>);
+pub struct ParseTrees<T = String>(pub Vec<Rc<Item<T>>>);
+
+// Memoized Leo paths for (StateSet, completed symbol)
+type LeoMemo<T> = HashMap<(usize, String), Option<Rc<LeoLink<T>>>>;
 
 ///////////////////////////////////////////////////////////////////////////////
 
-impl EarleyParser {
-    pub fn new(grammar: Grammar) -> EarleyParser {
-        EarleyParser{g: grammar, debug: false}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ParseError{index, ref lexeme, ref expected} => {
+                match *lexeme {
+                    Some(ref lexeme) =>
+                        write!(f, "Unexpected {:?} at token {}", lexeme, index)?,
+                    None =>
+                        write!(f, "Unexpected end of input at token {}", index)?,
+                }
+                if !expected.is_empty() {
+                    write!(f, ", expected one of: {}", expected.join(" "))?;
+                }
+                Ok(())
+            },
+            Error::MissingAction(ref rule) =>
+                write!(f, "Missing action for rule: {}", rule),
+            Error::MissingSym(ref sym) => write!(f, "Missing symbol: {}", sym),
+            Error::MissingRule(ref rule) => write!(f, "Missing rule: {}", rule),
+            Error::DuplicateSym(ref sym) =>
+                write!(f, "Duplicate symbol: {}", sym),
+            Error::DuplicateRule(ref rule) =>
+                write!(f, "Duplicate rule: {}", rule),
+            Error::InvalidWeight(ref rule) =>
+                write!(f, "Rule weight must be positive: {}", rule),
+            Error::Rejected(ref sym) =>
+                write!(f, "Every tree rejected by actions: {}", sym),
+            Error::Filtered(ref sym) =>
+                write!(f, "Every tree removed by filters: {}", sym),
+            Error::InvalidData(ref what) =>
+                write!(f, "Invalid grammar data: {}", what),
+            Error::InvalidRegex(ref what) => write!(f, "Invalid regex: {}", what),
+            Error::LimitExceeded(ref limit) =>
+                write!(f, "Parse aborted: {}", limit),
+        }
     }
+}
 
-    pub fn parse<S, SI>(&self, mut tok: SI) -> Result<ParseTrees, Error>
-            where S: AsRef<str>, SI: Iterator<Item=S> {
-
-        // 0. Populate S0, add items for each rule matching the start symbol
-        let s0: StateSet = self.g.rules_for(&self.g.start).into_iter()
-                    .map(|r| Item::predict_new(&r, 0))
-                    .collect();
+///////////////////////////////////////////////////////////////////////////////
 
-        let mut states = vec![s0];
+impl<T> EarleyParser<T> {
+    pub fn new(grammar: Grammar<T>) -> EarleyParser<T> {
+        let mut terminals: Vec<Arc<Symbol<T>>> = Vec::new();
+        for symbol in grammar.rules.iter().flat_map(|r| r.spec.iter()) {
+            if let Symbol::Terminal(_, _) = **symbol {
+                if !terminals.contains(symbol) {
+                    terminals.push(symbol.clone());
+                }
+            }
+        }
+        EarleyParser{g: grammar, debug: false, recovery: None,
+                     limits: Limits::default(), lookahead: true, leo: true,
+                     terminals}
+    }
 
-        // New states are generated from input stream (Scans)
-        for idx in 0.. {
-            if states.len() <= idx { break; }
+    // Lookahead is on by default, parse results are the same without it
+    // but StateSets hold every prediction.
+    pub fn lookahead(mut self, enabled: bool) -> Self {
+        self.lookahead = enabled;
+        self
+    }
 
-            // Predic/Complete until no new Items are added to the StateSet
-            loop {
-                let (new_items, prev_item_count) = {
-                    let state = &states[idx];
-                    let new_items: Vec<Item> = state.iter()
-                        .flat_map(|item| match item.next_symbol() {
+    // Leo's optimization is on by default, parse results are the same
+    // without it but right recursions add every item along their path.
+    pub fn leo(mut self, enabled: bool) -> Self {
+        self.leo = enabled;
+        self
+    }
 
-                            // Prediction: add rules starting with next symbol
-                            Some(Symbol::NonTerm(ref name)) =>
-                                self.g.rules_for(name).into_iter()
-                                    .map(|rule| Item::predict_new(&rule, idx))
-                                    .collect(),
+    // Names of the terminals that match 'token'
+    fn accepting(&self, token: &T) -> HashSet<String> {
+        self.terminals.iter()
+            .filter_map(|symbol| match **symbol {
+                Symbol::Terminal(ref name, ref matches) if matches(token) =>
+                    Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
 
-                            // Completion: add items with rules that completed
-                            None =>
-                                states[item.start].completed_at(item, idx),
+    // Without lookahead everything is predicted, before knowing the next
+    // token only nullable rules are (they may complete right away).
+    fn no_lookahead(&self) -> Option<HashSet<String>> {
+        if self.lookahead { Some(HashSet::new()) } else { None }
+    }
 
-                            // Scans: these will populate next state, ignore
-                            Some(Symbol::Terminal(_, _)) => Vec::new(),
+    fn new_session(&self, goal: &str, anchored: bool) -> ParseSession<'_, T> {
+        // 0. Populate S0, add items for each rule matching the goal symbol
+        let s0: StateSet<T> = self.g.rules_for(goal).into_iter()
+                    .map(|r| Item::predict_new(&r, 0))
+                    .collect();
+        let mut session = ParseSession{
+            parser: self,
+            goal: goal.to_string(),
+            anchored,
+            items: s0.len(),
+            states: vec![s0],
+            leo_memo: LeoMemo::new(),
+            aborted: None,
+            stats: Stats::default(),
+        };
+        for item in session.states[0].iter() {
+            session.stats.count(Step::Prediction, &item.rule);
+        }
+        // sessions can't fail to start, pushing will return the error
+        if let Err(limit) = session.close_last(self.no_lookahead().as_ref()) {
+            session.aborted = Some(limit);
+        }
+        session
+    }
 
-                        }).collect();
+    // Start an incremental parse, tokens are fed one at a time
+    pub fn session(&self) -> ParseSession<'_, T> {
+        self.new_session(&self.g.start, true)
+    }
 
-                    (new_items, state.len())
-                };
+    // Incremental parse looking for 'symbol' starting at any token.
+    // Tokens that can't be scanned are skipped over instead of failing.
+    pub fn span_session(&self, symbol: &str)
+            -> Result<ParseSession<'_, T>, Error> {
+        if self.g.rules_for(symbol).is_empty() {
+            return Err(Error::MissingSym(symbol.to_string()));
+        }
+        Ok(self.new_session(symbol, false))
+    }
 
-                // keep adding new items to this StateSet until no more changes
-                let state = &mut states[idx];
-                state.extend(new_items);
-                if state.len() == prev_item_count { break; }
-            }
+    // Find every span of the input where 'symbol' is recognized
+    pub fn parse_spans<S, SI>(&self, symbol: &str, tok: SI)
+            -> Result<Vec<Span<T>>, Error>
+            where T: fmt::Display, S: Into<T>, SI: Iterator<Item=S> {
+        let mut session = self.span_session(symbol)?;
+        for lexeme in tok {
+            session.push(lexeme)?;
+        }
+        Ok(session.spans())
+    }
 
-            // Bootstrap Si+1 next state with rules that accept the next token
-            if let Some(lexeme) = tok.next() {
-                let scans = states[idx]
-                    .advanced_by_scan(lexeme.as_ref(), idx+1)
-                    .into_iter()
-                    .collect();
-                states.push(scans);
-            }
+    pub fn parse<S, SI>(&self, tok: SI) -> Result<ParseTrees<T>, Error>
+            where T: fmt::Display, S: Into<T>, SI: Iterator<Item=S> {
+        let mut session = self.session();
+        for lexeme in tok {
+            session.push(lexeme)?;
         }
 
         // Verbose, debug state-sets
         if self.debug {
-            for (idx, stateset) in states.iter().enumerate() {
+            for (idx, stateset) in session.states.iter().enumerate() {
                 eprintln!("=== {} ===", idx);
                 for item in stateset.iter() { eprintln!("{:?}", item); }
             }
             eprintln!("=========");
         }
+        session.parse_trees()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+// An in-progress parse. Each token pushed adds a StateSet to the chart.
+// Anchored sessions look for 'goal' spanning the whole input, otherwise
+// 'goal' is predicted at each StateSet to find it anywhere in the input.
+pub struct ParseSession<'a, T: 'a = String> {
+    parser: &'a EarleyParser<T>,
+    goal: String,
+    anchored: bool,
+    // number of items in the chart
+    items: usize,
+    states: Vec<StateSet<T>>,
+    leo_memo: LeoMemo<T>,
+    // a limit was hit while building the last StateSet, it's incomplete
+    aborted: Option<Limit>,
+    // work done so far, set_items is filled in by stats()
+    stats: Stats,
+}
+
+// A sub-sequence of the input (start..end token indexes) recognized as a symbol
+#[derive(Debug)]
+pub struct Span<T = String> {
+    pub start: usize,
+    pub end: usize,
+    pub trees: ParseTrees<T>,
+}
+
+// Sessions share the chart built so far, tokens don't need to be Clone
+impl<'a, T> Clone for ParseSession<'a, T> {
+    fn clone(&self) -> Self {
+        ParseSession{
+            parser: self.parser,
+            goal: self.goal.clone(),
+            anchored: self.anchored,
+            items: self.items,
+            states: self.states.clone(),
+            leo_memo: self.leo_memo.clone(),
+            aborted: self.aborted.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<'a, T> ParseSession<'a, T> {
+    // Check if a complete 'head' item from 'start' is a result of the session
+    fn is_goal(&self, head: &str, start: usize) -> bool {
+        head == self.goal && (start == 0 || !self.anchored)
+    }
+
+    // Leo path for 'symbol' items started at 'at' that will be completed.
+    // Only StateSets that are no longer growing can be queried.
+    fn leo_link(&mut self, symbol: &str, at: usize) -> Option<Rc<LeoLink<T>>> {
+        let key = (at, symbol.to_string());
+        if let Some(link) = self.leo_memo.get(&key) {
+            return link.clone();
+        }
+        // unit rule cycles (A -> B, B -> A) end the path when revisited
+        self.leo_memo.insert(key.clone(), None);
+        let waiting: Vec<_> = self.states[at].iter().filter(|item|
+            match item.next_symbol() {
+                Some(Symbol::NonTerm(name)) => name == symbol,
+                _ => false,
+            }).take(2).cloned().collect();
+        let link = match waiting.as_slice() {
+            [source] if source.dot + 1 == source.rule.spec.len() => {
+                // complete goal items must stay in the chart to be found
+                let above = if self.is_goal(&source.rule.head, source.start) {
+                    None
+                } else {
+                    self.leo_link(&source.rule.head, source.start)
+                };
+                Some(Rc::new(LeoLink{source: source.clone(), above}))
+            },
+            _ => None,
+        };
+        self.leo_memo.insert(key, link.clone());
+        link
+    }
+
+    // Add to the last StateSet predictions for rules of 'symbol', with
+    // 'lookahead' only those that may start with one of its terminals.
+    // Returns the Items that weren't in the StateSet yet.
+    fn predict(&mut self, symbol: &str, lookahead: Option<&HashSet<String>>)
+            -> Vec<Rc<Item<T>>> {
+        let idx = self.states.len() - 1;
+        let grammar = &self.parser.g;
+        let mut new_items = Vec::new();
+        for rule in grammar.rules_for(symbol) {
+            if let Some(terminals) = lookahead {
+                if !grammar.starts_with(&rule.spec, terminals) { continue; }
+            }
+            if let Some(item) = self.states[idx].insert(
+                    Item::predict_new(&rule, idx)) {
+                self.stats.count(Step::Prediction, &rule);
+                self.items += 1;
+                new_items.push(item);
+            }
+        }
+        new_items
+    }
+
+    // Predict/Complete each Item of the last StateSet once.
+    // With 'lookahead' only rules that may start with one of those terminals
+    // are predicted, see reclose_last to add the rules of other terminals.
+    // Stops if the StateSet or the chart grow past their limits.
+    fn close_last(&mut self, lookahead: Option<&HashSet<String>>)
+            -> Result<(), Limit> {
+        let idx = self.states.len() - 1;
+        let mut pending: Vec<_> = self.states[idx].iter().cloned().collect();
+        // unanchored sessions may find the goal starting at any StateSet
+        if !self.anchored && idx > 0 {
+            let goal = self.goal.clone();
+            pending.extend(self.predict(&goal, lookahead));
+        }
+        self.close_pending(lookahead, pending, HashSet::new()).map(|_| ())
+    }
 
-        // Check that at least one item is a. complete, b. starts at the idx 0,
-        // and c. that the name of the rule matches the starting symbol.
-        // It spans the whole input because we search at the last stateset
-        let parse_trees: Vec<_> = states.pop().ok_or(Error::ParseError)?
+    // Close the last StateSet again for the 'lookahead' of the next token.
+    // Its Items were processed already, only rules of the symbols they wait
+    // on that 'lookahead' allows are missing (and what follows from them).
+    // Returns the Items added.
+    fn reclose_last(&mut self, lookahead: &HashSet<String>)
+            -> Result<Vec<Rc<Item<T>>>, Limit> {
+        let idx = self.states.len() - 1;
+        let mut waited: HashSet<String> = self.states[idx].iter()
+            .filter_map(|item| match item.next_symbol() {
+                Some(Symbol::NonTerm(name)) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        if !self.anchored && idx > 0 {
+            waited.insert(self.goal.clone());
+        }
+        let mut added = Vec::new();
+        for symbol in &waited {
+            added.extend(self.predict(symbol, Some(lookahead)));
+        }
+        let pending = added.clone();
+        added.extend(self.close_pending(Some(lookahead), pending, waited)?);
+        Ok(added)
+    }
+
+    // Predict/Complete each of the 'pending' Items of the last StateSet,
+    // new ones are queued as they're added. Symbols in 'predicted' had
+    // their rules predicted already. Returns the Items added.
+    fn close_pending(&mut self, lookahead: Option<&HashSet<String>>,
+                     mut pending: Vec<Rc<Item<T>>>,
+                     mut predicted: HashSet<String>)
+            -> Result<Vec<Rc<Item<T>>>, Limit> {
+        let idx = self.states.len() - 1;
+        let grammar = &self.parser.g;
+        let predictable = |spec: &[Arc<Symbol<T>>]| match lookahead {
+            Some(terminals) => grammar.starts_with(spec, terminals),
+            None => true,
+        };
+        let limits = &self.parser.limits;
+        limits.check_items(self.states[idx].len(), self.items)?;
+        let mut added = Vec::new();
+        while let Some(item) = pending.pop() {
+            let (step, new_items) = match item.next_symbol() {
+
+                // Prediction: add rules starting with next symbol.
+                // Nullable symbols are skipped right away (Aycock-Horspool)
+                Some(Symbol::NonTerm(ref name)) => {
+                    let mut new_items = Vec::new();
+                    if predicted.insert(name.clone()) {
+                        new_items.extend(grammar.rules_for(name).into_iter()
+                            .filter(|rule| predictable(&rule.spec))
+                            .map(|rule| Item::predict_new(&rule, idx)));
+                    }
+                    if grammar.is_nullable(name) {
+                        new_items.extend(
+                            self.states[idx].advanced_by_null(&item, idx));
+                    }
+                    (Step::Prediction, new_items)
+                },
+
+                // Completion: add items with rules that completed
+                // Leo paths jump to their top item directly
+                None if self.parser.leo && item.start < idx =>
+                        match self.leo_link(&item.rule.head, item.start) {
+                    Some(ref link) if link.above.is_some() =>
+                        (Step::Completion,
+                         vec![Item::leo_new(link, &item, idx)]),
+                    _ => (Step::Completion,
+                          self.states[item.start].completed_at(&item, idx)),
+                },
+                None => (Step::Completion,
+                         self.states[item.start].completed_at(&item, idx)),
+
+                // Scans: these will populate next state, ignore
+                Some(Symbol::Terminal(_, _)) => continue,
+            };
+            // only Items new to this StateSet need processing
+            let state = &mut self.states[idx];
+            let before = state.len();
+            for new_item in new_items {
+                if let Some(new_item) = state.insert(new_item) {
+                    self.stats.count(step, &new_item.rule);
+                    pending.push(new_item.clone());
+                    added.push(new_item);
+                }
+            }
+            self.items += state.len() - before;
+            limits.check_items(state.len(), self.items)?;
+        }
+        Ok(added)
+    }
+
+    fn abort(&mut self, limit: Limit) -> Error {
+        self.aborted = Some(limit.clone());
+        Error::LimitExceeded(limit)
+    }
+
+    // Bootstrap the next StateSet with rules that accept the token.
+    // If the token can't be scanned the session is left untouched.
+    pub fn push<S: Into<T>>(&mut self, token: S) -> Result<(), Error>
+            where T: fmt::Display {
+        if let Some(ref limit) = self.aborted {
+            return Err(Error::LimitExceeded(limit.clone()));
+        }
+        let idx = self.states.len() - 1;
+        self.parser.limits.check_token(idx).map_err(Error::LimitExceeded)?;
+        let token = Rc::new(token.into());
+        let (items, stats) = (self.items, self.stats.clone());
+        let mut predicted = Vec::new();
+        if self.parser.lookahead {
+            let terminals = self.parser.accepting(&token);
+            match self.reclose_last(&terminals) {
+                Ok(added) => predicted = added,
+                Err(limit) => return Err(self.abort(limit)),
+            }
+        }
+        let scans: StateSet<T> = self.states[idx]
+            .advanced_by_scan(&token, idx+1)
             .into_iter()
-            .filter(|item| item.start == 0 && item.complete() &&
-                           item.rule.head == self.g.start)
             .collect();
+        // no item could scan the token, parsing can't make progress
+        if scans.is_empty() && self.anchored {
+            // Items predicted for the token only add to the last StateSet
+            for item in &predicted {
+                self.states[idx].remove(item);
+            }
+            self.items = items;
+            self.stats = stats;
+            return Err(Error::ParseError{
+                index: idx,
+                lexeme: Some(token.to_string()),
+                expected: self.expected(),
+            });
+        }
+        self.items += scans.len();
+        for item in scans.iter() {
+            self.stats.count(Step::Scan, &item.rule);
+        }
+        self.states.push(scans);
+        let lookahead = self.parser.no_lookahead();
+        self.close_last(lookahead.as_ref()).map_err(|limit| self.abort(limit))
+    }
+
+    // Work done by the session so far, see Stats::top_rules
+    pub fn stats(&self) -> Stats {
+        Stats{
+            set_items: self.states.iter().map(|s| s.len()).collect(),
+            ..self.stats.clone()
+        }
+    }
+
+    // Number of tokens consumed so far
+    pub fn position(&self) -> usize { self.states.len() - 1 }
+
+    // StateSets built since 'position'. Sessions that consumed the same
+    // tokens up to there accept the same input if these are equal.
+    pub fn states_since(&self, position: usize) -> &[StateSet<T>] {
+        &self.states[position..]
+    }
+
+    // Terminals that would be accepted by the next push. Predictions
+    // may be missing from the last StateSet, use FIRST sets instead.
+    pub fn expected(&self) -> Vec<String> {
+        let names: BTreeSet<_> = self.states.last()
+            .expect("BUG: session without StateSets").iter()
+            .flat_map(|item| self.parser.g.first_of(&item.rule.spec[item.dot..]))
+            .collect();
+        names.into_iter().collect()
+    }
+
+    // Items that are a. complete, b. start at the idx 0, and c. the name of
+    // the rule matches the goal symbol. They span the whole input
+    // consumed so far because we search at the last stateset
+    fn roots(&self) -> Vec<Rc<Item<T>>> {
+        self.states.last().expect("BUG: session without StateSets").iter()
+            .filter(|item| item.start == 0 && item.complete() &&
+                           item.rule.head == self.goal)
+            .cloned()
+            .collect()
+    }
+
+    // Every span of the input consumed so far where the goal was recognized.
+    // Spans are sorted by start, then end position.
+    pub fn spans(&self) -> Vec<Span<T>> {
+        let mut spans = Vec::new();
+        for (end, stateset) in self.states.iter().enumerate() {
+            let mut by_start = BTreeMap::new();
+            for item in stateset.iter() {
+                if item.complete() && self.is_goal(&item.rule.head, item.start) {
+                    by_start.entry(item.start)
+                        .or_insert_with(Vec::new).push(item.clone());
+                }
+            }
+            let filters = &self.parser.g.filters;
+            spans.extend(by_start.into_iter()
+                .map(|(start, roots)| (start, filters.apply(&self.states, roots)))
+                .filter(|(_, roots)| !roots.is_empty())
+                .map(|(start, roots)| Span{start, end, trees: ParseTrees(roots)}));
+        }
+        spans.sort_by_key(|span| (span.start, span.end));
+        spans
+    }
 
+    // Check if tokens pushed so far make a complete parse
+    pub fn is_complete(&self) -> bool { !self.roots().is_empty() }
+
+    pub fn parse_trees(&self) -> Result<ParseTrees<T>, Error> {
+        if let Some(ref limit) = self.aborted {
+            return Err(Error::LimitExceeded(limit.clone()));
+        }
+        let parse_trees = self.roots();
+        if parse_trees.is_empty() {
+            return Err(Error::ParseError{
+                index: self.position(), lexeme: None, expected: self.expected()});
+        }
+        let parse_trees = self.parser.g.filters.apply(&self.states, parse_trees);
         if parse_trees.is_empty() {
-            return Err(Error::ParseError);
+            return Err(Error::Filtered(self.goal.clone()));
         }
         Ok(ParseTrees(parse_trees))
     }
+
+    // Graphviz rendering of the chart built so far (eg: after a failed push)
+    pub fn to_dot(&self) -> String where T: fmt::Debug {
+        Graph::chart(&self.states, &self.roots()).to_dot()
+    }
+
+    // The chart as JSON: items with their back-pointers, see export.rs
+    pub fn to_json(&self) -> String where T: fmt::Debug {
+        Graph::chart(&self.states, &self.roots()).to_json()
+    }
+}
+
+impl<T: fmt::Debug> ParseTrees<T> {
+    // Graphviz rendering of the back-pointer forest, ambiguous items in red
+    pub fn to_dot(&self) -> String { Graph::forest(&self.0).to_dot() }
+
+    pub fn to_json(&self) -> String { Graph::forest(&self.0).to_json() }
 }
 
 ///////////////////////////////////////////////////////////////////////////////
@@ -121,8 +586,20 @@ mod tests {
     }
 
     fn fail(parser: &EarleyParser, input: &str) {
+        match parser.parse(input.split_whitespace()) {
+            Err(Error::ParseError{..}) => (),
+            other => panic!("Expected ParseError, got {:?}", other),
+        }
+    }
+
+    fn fail_at(parser: &EarleyParser, input: &str, index: usize,
+               lexeme: Option<&str>, expected: &[&str]) {
         assert_eq!(parser.parse(input.split_whitespace()).unwrap_err(),
-                   Error::ParseError);
+                   Error::ParseError{
+                       index,
+                       lexeme: lexeme.map(|l| l.to_string()),
+                       expected: expected.iter().map(|e| e.to_string()).collect(),
+                   });
     }
 
     #[test]
@@ -152,6 +629,120 @@ mod tests {
           .expect("Bad Grammar");
         let p = EarleyParser::new(grammar);
         fail(&p, "1 +");
+        fail_at(&p, "1 +", 2, None, &["Number"]);
+        fail_at(&p, "1 + + 2", 2, Some("+"), &["Number"]);
+        fail_at(&p, "+", 0, Some("+"), &["Number"]);
+        fail_at(&p, "1 2", 1, Some("2"), &["[+-]"]);
+    }
+
+    #[test]
+    fn session() {
+        let grammar = GrammarBuilder::default()
+          .nonterm("Sum")
+          .terminal("Number", |n| n.chars().all(|c| "1234".contains(c)))
+          .terminal("[+-]", |n| n.len() == 1 && "+-".contains(n))
+          .rule("Sum", &["Sum", "[+-]", "Number"])
+          .rule("Sum", &["Number"])
+          .into_grammar("Sum")
+          .expect("Bad Grammar");
+        let p = EarleyParser::new(grammar);
+        let mut session = p.session();
+        assert_eq!(session.expected(), vec!["Number"]);
+        assert!(!session.is_complete());
+        assert!(session.push("1").is_ok());
+        assert!(session.is_complete());
+        assert_eq!(session.expected(), vec!["[+-]"]);
+        // rejected tokens aren't consumed
+        let stats = session.stats();
+        assert_eq!(session.push("2").unwrap_err(), Error::ParseError{
+            index: 1, lexeme: Some("2".to_string()),
+            expected: vec!["[+-]".to_string()]});
+        assert_eq!(session.position(), 1);
+        assert_eq!(session.stats(), stats);
+        assert!(session.push("+").is_ok());
+        assert!(!session.is_complete());
+        assert!(session.parse_trees().is_err());
+        assert!(session.push("3").is_ok());
+        assert_eq!(session.position(), 3);
+        assert_eq!(session.parse_trees().unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn spans() {
+        // Time -> next Day | Day
+        // Day  -> friday | monday
+        let grammar = GrammarBuilder::default()
+          .nonterm("Time")
+          .nonterm("Day")
+          .terminal("next", |n| n == "next")
+          .terminal("day", |n| n == "friday" || n == "monday")
+          .rule("Time", &["next", "Day"])
+          .rule("Time", &["Day"])
+          .rule("Day", &["day"])
+          .into_grammar("Time")
+          .expect("Bad Grammar");
+        let p = EarleyParser::new(grammar);
+        let input = "lets meet next friday or monday at the office";
+        let spans: Vec<_> = p.parse_spans("Time", input.split_whitespace())
+            .unwrap().into_iter()
+            .map(|span| (span.start, span.end))
+            .collect();
+        assert_eq!(spans, vec![(2, 4), (3, 4), (5, 6)]);
+        let spans: Vec<_> = p.parse_spans("Day", input.split_whitespace())
+            .unwrap().into_iter()
+            .map(|span| (span.start, span.end))
+            .collect();
+        assert_eq!(spans, vec![(3, 4), (5, 6)]);
+        assert!(p.parse_spans("Time", "at the office".split_whitespace())
+                .unwrap().is_empty());
+        assert_eq!(p.parse_spans("Nope", "next".split_whitespace()).unwrap_err(),
+                   Error::MissingSym("Nope".to_string()));
+        // anchored parse still requires the whole input
+        fail(&p, input);
+    }
+
+    #[test]
+    fn spans_right_recurse() {
+        // P -> N ^ P | N
+        // N -> [0-9]
+        let grammar = GrammarBuilder::default()
+          .nonterm("P")
+          .nonterm("N")
+          .terminal("[^]", |n| n == "^")
+          .terminal("[0-9]", |n| "1234567890".contains(n))
+          .rule("P", &["N", "[^]", "P"])
+          .rule("P", &["N"])
+          .rule("N", &["[0-9]"])
+          .into_grammar("P")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar);
+        // inner spans must survive Leo paths skipping completions
+        let spans: Vec<_> = p.parse_spans("P", "x 1 ^ 2 ^ 3 x".split_whitespace())
+            .unwrap().into_iter()
+            .map(|span| (span.start, span.end))
+            .collect();
+        assert_eq!(spans, vec![(1, 2), (1, 4), (1, 6), (3, 4), (3, 6), (5, 6)]);
+    }
+
+    #[test]
+    fn error_message() {
+        let grammar = GrammarBuilder::default()
+          .nonterm("Start")
+          .terminal("+", |n| n == "+")
+          .terminal("-", |n| n == "-")
+          .rule("Start", &["+", "+"])
+          .rule("Start", &["+", "-"])
+          .into_grammar("Start")
+          .expect("Bad Grammar");
+        let p = EarleyParser::new(grammar);
+        let err = p.parse("+ *".split_whitespace()).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "Unexpected \"*\" at token 1, expected one of: + -");
+        let err = p.parse("+".split_whitespace()).unwrap_err();
+        assert_eq!(err.to_string(),
+                   "Unexpected end of input at token 1, expected one of: + -");
+        let err = p.parse("+ + +".split_whitespace()).unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected \"+\" at token 2");
     }
 
     #[test]
@@ -215,6 +806,74 @@ mod tests {
         fail(&p, "1 2 ^ 4");
     }
 
+    #[test]
+    fn right_recurse_long() {
+        // P -> N ^ P | N
+        // N -> [0-9]
+        let grammar = GrammarBuilder::default()
+          .nonterm("P")
+          .nonterm("N")
+          .terminal("[^]", |n| n == "^")
+          .terminal("[0-9]", |n| "1234567890".contains(n))
+          .rule("P", &["N", "[^]", "P"])
+          .rule("P", &["N"])
+          .rule("N", &["[0-9]"])
+          .into_grammar("P")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar);
+        // Without Leo's optimization every StateSet after a number holds a
+        // completed P item for each pending '^', ie: quadratic item count.
+        for &len in &[100, 1000, 4000] {
+            let mut session = p.session();
+            for lexeme in vec!["2"; len].join(" ^ ").split_whitespace() {
+                session.push(lexeme).unwrap();
+            }
+            let items: usize = session.states.iter().map(|s| s.len()).sum();
+            assert!(items < 5 * session.states.len(),
+                    "{} items for {} tokens", items, session.states.len());
+        }
+        good(&p, &vec!["2"; 2000].join(" ^ "));
+    }
+
+    #[test]
+    fn lookahead_predictions() {
+        // Cmd -> Action Cmd | <e>
+        // Action -> kw0 arg | kw1 arg | ... | kw19 arg
+        let mut builder = GrammarBuilder::default()
+          .nonterm("Cmd")
+          .nonterm("Action")
+          .terminal("arg", |n| n == "arg");
+        for i in 0..20 {
+            let kw = format!("kw{}", i);
+            let tok = kw.clone();
+            builder.add_terminal(kw.clone(), move |n: &String| *n == tok, false);
+            builder.add_rule("Action", &[kw, "arg".to_string()]);
+        }
+        let grammar = builder
+          .rule("Cmd", &["Action", "Cmd"])
+          .rule::<_, &str>("Cmd", &[])
+          .into_grammar("Cmd")
+          .expect("Bad grammar");
+        let filtered = EarleyParser::new(grammar.clone());
+        let unfiltered = EarleyParser::new(grammar).lookahead(false);
+        let input = "kw3 arg kw17 arg kw3 arg";
+        let count = |p: &EarleyParser| {
+            let mut session = p.session();
+            for lexeme in input.split_whitespace() {
+                session.push(lexeme).unwrap();
+            }
+            assert!(session.is_complete());
+            session.states.iter().map(|s| s.len()).sum::<usize>()
+        };
+        // one Action rule predicted per keyword instead of all 20
+        assert!(count(&filtered) * 4 < count(&unfiltered));
+        assert_eq!(filtered.session().expected(),
+                   unfiltered.session().expected());
+        assert_eq!(filtered.session().expected().len(), 20);
+        fail_at(&filtered, "kw3 arg kw3", 3, None, &["arg"]);
+        fail_at(&filtered, "kw3 kw3", 1, Some("kw3"), &["arg"]);
+    }
+
     #[test]
     fn bogus_empty() {
         // A -> <empty> | B
@@ -233,6 +892,34 @@ mod tests {
         fail(&p, "X");
     }
 
+    #[test]
+    fn nullable_chain() {
+        // S -> A A A x
+        // A -> B | a
+        // B -> C
+        // C -> <epsilon>
+        let grammar = GrammarBuilder::default()
+          .nonterm("S")
+          .nonterm("A")
+          .nonterm("B")
+          .nonterm("C")
+          .terminal("a", |l| l == "a")
+          .terminal("x", |l| l == "x")
+          .rule("S", &["A", "A", "A", "x"])
+          .rule("A", &["B"])
+          .rule("A", &["a"])
+          .rule("B", &["C"])
+          .rule::<_, &str>("C", &[])
+          .into_grammar("S")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar);
+        good(&p, "x");
+        good(&p, "a x");
+        good(&p, "a a a x");
+        fail(&p, "a a a a x");
+        fail_at(&p, "a", 1, None, &["a", "x"]);
+    }
+
     #[test]
     fn bogus_epsilon() {
         // Grammar for balanced parenthesis
@@ -1,8 +1,9 @@
 #![deny(warnings)]
 
 use grammar::{GrammarBuilder, Grammar};
-use parser::EarleyParser;
-use trees::EarleyForest;
+use parser::{EarleyParser, Error};
+use trees::{EarleyForest, ActionKey};
+use sppf::Sppf;
 use std::fmt;
 
 
@@ -71,6 +72,7 @@ mod math {
     #[test]
     fn math_grammar_test() {
         use parser::EarleyParser;
+        use sppf::Sppf;
         use super::{Tree, tree_evaler};
         fn node(rule: &str, subtree: Vec<Tree>) -> Tree {
             Tree::Node(rule.to_string(), subtree)
@@ -115,8 +117,12 @@ mod math {
         let grammar = grammar_math();
         let p = EarleyParser::new(grammar.clone());
         let pout = p.parse("1 + ( 2 * 3 - 4 )".split_whitespace()).unwrap();
-        let trees = tree_evaler(grammar).eval_all(&pout).unwrap();
-        assert_eq!(trees, vec![tree]);
+        let evaler = tree_evaler(grammar);
+        let trees = evaler.eval_all(&pout).unwrap();
+        assert_eq!(trees, vec![tree.clone()]);
+        let shared = evaler.eval_sppf(&Sppf::new(&pout),
+                                      |_| panic!("Grammar isn't ambiguous"));
+        assert_eq!(shared.unwrap(), tree);
     }
 }
 
@@ -188,6 +194,35 @@ fn grammar_ambiguous_epsilon() {
 }
 
 
+#[test]
+fn nullable_ambiguous() {
+    // S -> A A x
+    // A -> <e> | B
+    // B -> <e>
+    let grammar = GrammarBuilder::default()
+      .nonterm("S")
+      .nonterm("A")
+      .nonterm("B")
+      .terminal("x", |n| n == "x")
+      .rule("S", &["A", "A", "x"])
+      .rule::<_, &str>("A", &[])
+      .rule("A", &["B"])
+      .rule::<_, &str>("B", &[])
+      .into_grammar("S")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar.clone());
+    let pout = p.parse("x".split_whitespace()).unwrap();
+    let evaler = tree_evaler(grammar);
+    assert_eq!(evaler.num_trees(&pout), Some(4));
+    let trees = evaler.eval_all(&pout).unwrap();
+    check_trees(&trees, vec![
+        r#"Node("S -> A A x", [Node("A -> ", []), Node("A -> ", []), Leaf("x", "x")])"#,
+        r#"Node("S -> A A x", [Node("A -> ", []), Node("A -> B", [Node("B -> ", [])]), Leaf("x", "x")])"#,
+        r#"Node("S -> A A x", [Node("A -> B", [Node("B -> ", [])]), Node("A -> ", []), Leaf("x", "x")])"#,
+        r#"Node("S -> A A x", [Node("A -> B", [Node("B -> ", [])]), Node("A -> B", [Node("B -> ", [])]), Leaf("x", "x")])"#,
+    ]);
+}
+
 #[test]
 fn left_recurse() {
     // S -> S + N | N
@@ -231,7 +266,7 @@ fn right_recurse() {
       .expect("Bad grammar");
     let p = EarleyParser::new(grammar.clone());
     let pout = p.parse("1 ^ 2".split_whitespace()).unwrap();
-    let tree = tree_evaler(grammar).eval(&pout).unwrap();
+    let tree = tree_evaler(grammar.clone()).eval(&pout).unwrap();
     check_trees(&vec![tree], vec![
         concat!(
             r#"Node("P -> N [^] P", ["#,
@@ -240,6 +275,106 @@ fn right_recurse() {
                 r#"Node("P -> N", ["#,
                     r#"Node("N -> [0-9]", [Leaf("[0-9]", "2")])])])"#)
     ]);
+    // longer chains are completed through Leo paths, trees must be rebuilt
+    let pout = p.parse("1 ^ 2 ^ 3 ^ 4".split_whitespace()).unwrap();
+    let evaler = tree_evaler(grammar);
+    assert_eq!(evaler.num_trees(&pout), Some(1));
+    let trees = evaler.eval_all(&pout).unwrap();
+    check_trees(&trees, vec![
+        concat!(
+            r#"Node("P -> N [^] P", ["#,
+                r#"Node("N -> [0-9]", [Leaf("[0-9]", "1")]), "#,
+                r#"Leaf("[^]", "^"), "#,
+                r#"Node("P -> N [^] P", ["#,
+                    r#"Node("N -> [0-9]", [Leaf("[0-9]", "2")]), "#,
+                    r#"Leaf("[^]", "^"), "#,
+                    r#"Node("P -> N [^] P", ["#,
+                        r#"Node("N -> [0-9]", [Leaf("[0-9]", "3")]), "#,
+                        r#"Leaf("[^]", "^"), "#,
+                        r#"Node("P -> N", ["#,
+                            r#"Node("N -> [0-9]", [Leaf("[0-9]", "4")])])])])])"#)
+    ]);
+}
+
+#[test]
+fn right_recurse_ambiguous() {
+    // P -> a P | X | Y
+    // X -> b ; Y -> b
+    // The Leo path of P is completed by both X and Y, every rebuilt item
+    // along the path must keep the two of them
+    let grammar = GrammarBuilder::default()
+      .nonterm("P")
+      .nonterm("X")
+      .nonterm("Y")
+      .terminal("a", |n| n == "a")
+      .terminal("b", |n| n == "b")
+      .rule("P", &["a", "P"])
+      .rule("P", &["X"])
+      .rule("P", &["Y"])
+      .rule("X", &["b"])
+      .rule("Y", &["b"])
+      .into_grammar("P")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar.clone());
+    let evaler = tree_evaler(grammar);
+    for input in &["a a b", "a a a a b"] {
+        let pout = p.parse(input.split_whitespace()).unwrap();
+        assert_eq!(evaler.num_trees(&pout), Some(2));
+        assert_eq!(evaler.eval_all(&pout).unwrap().len(), 2);
+    }
+}
+
+#[test]
+fn leo_shared_items() {
+    // Items rebuilt along Leo paths may already be in the chart through
+    // other derivations, trees are the same with Leo's optimization or not
+    let count = |grammar: Grammar, input: &str, leo: bool| {
+        let p = EarleyParser::new(grammar).leo(leo);
+        let ps = p.parse(input.split_whitespace()).unwrap();
+        EarleyForest::new(|_, _| ()).num_trees(&ps)
+    };
+    // N0 -> a | N2 | b N3 ; N1 -> a a a | <e> | a
+    // N2 -> a | <e> | N3 N1 ; N3 -> a N3 | <e> | a N1 b
+    let grammar = GrammarBuilder::default()
+      .nonterm("N0")
+      .nonterm("N1")
+      .nonterm("N2")
+      .nonterm("N3")
+      .terminal("a", |n| n == "a")
+      .terminal("b", |n| n == "b")
+      .rule("N0", &["a"])
+      .rule("N0", &["N2"])
+      .rule("N0", &["b", "N3"])
+      .rule("N1", &["a", "a", "a"])
+      .rule::<_, &str>("N1", &[])
+      .rule("N1", &["a"])
+      .rule("N2", &["a"])
+      .rule::<_, &str>("N2", &[])
+      .rule("N2", &["N3", "N1"])
+      .rule("N3", &["a", "N3"])
+      .rule::<_, &str>("N3", &[])
+      .rule("N3", &["a", "N1", "b"])
+      .into_grammar("N0")
+      .expect("Bad grammar");
+    assert_eq!(count(grammar.clone(), "a", false), Some(4));
+    assert_eq!(count(grammar, "a", true), Some(4));
+    // N0 -> a N1 N0 | b N1 | a N1 ; N1 -> a N0 | <e> | b N1 N0
+    let grammar = GrammarBuilder::default()
+      .nonterm("N0")
+      .nonterm("N1")
+      .terminal("a", |n| n == "a")
+      .terminal("b", |n| n == "b")
+      .rule("N0", &["a", "N1", "N0"])
+      .rule("N0", &["b", "N1"])
+      .rule("N0", &["a", "N1"])
+      .rule("N1", &["a", "N0"])
+      .rule::<_, &str>("N1", &[])
+      .rule("N1", &["b", "N1", "N0"])
+      .into_grammar("N0")
+      .expect("Bad grammar");
+    let input = "b b a b a b";
+    assert_eq!(count(grammar.clone(), input, false), Some(4));
+    assert_eq!(count(grammar, input, true), Some(4));
 }
 
 #[test]
@@ -280,10 +415,77 @@ fn math_ambiguous_catalan() {
       .expect("Bad grammar");
     let p = EarleyParser::new(grammar.clone());
     let pout = p.parse("0 + 1 + 2 + 3 + 4 + 5".split_whitespace()).unwrap();
-    let trees = tree_evaler(grammar).eval_all(&pout).unwrap();
+    let evaler = tree_evaler(grammar);
+    let trees = evaler.eval_all(&pout).unwrap();
     // number of trees here should match Catalan numbers
     // https://en.wikipedia.org/wiki/Catalan_number
     assert_eq!(trees.len(), 42);
+    assert_eq!(evaler.num_trees(&pout), Some(42));
+}
+
+#[test]
+fn num_trees() {
+    // S -> S S X | b
+    // X -> <e>
+    let grammar = GrammarBuilder::default()
+      .nonterm("S")
+      .nonterm("X")
+      .terminal("b", |n| n == "b")
+      .rule("S", &["S", "S", "X"])
+      .rule::<_, &str>("X", &[])
+      .rule("S", &["b"])
+      .into_grammar("S")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar.clone());
+    let evaler = tree_evaler(grammar);
+    let pout = p.parse("b".split_whitespace()).unwrap();
+    assert_eq!(evaler.num_trees(&pout), Some(1));
+    let pout = p.parse("b b b".split_whitespace()).unwrap();
+    assert_eq!(evaler.num_trees(&pout), Some(2));
+    // Catalan(29) without enumerating trees
+    let pout = p.parse(vec!["b"; 30].into_iter()).unwrap();
+    assert_eq!(evaler.num_trees(&pout), Some(1002242216651368));
+    // Catalan(39) doesn't fit
+    let pout = p.parse(vec!["b"; 40].into_iter()).unwrap();
+    assert_eq!(evaler.num_trees(&pout), Some(u64::MAX));
+}
+
+#[test]
+fn num_trees_cyclic() {
+    // A -> <empty> | B
+    // B -> A
+    let grammar = GrammarBuilder::default()
+      .nonterm("A")
+      .nonterm("B")
+      .rule::<_, &str>("A", &[])
+      .rule("A", &["B"])
+      .rule("B", &["A"])
+      .into_grammar("A")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar.clone());
+    let pout = p.parse("".split_whitespace()).unwrap();
+    assert_eq!(tree_evaler(grammar).num_trees(&pout), None);
+}
+
+#[test]
+fn sppf_ambiguous_long_input() {
+    // S -> S S | b
+    // Count trees folding ambiguous nodes instead of enumerating them
+    let grammar = GrammarBuilder::default()
+      .nonterm("S")
+      .terminal("b", |n| n == "b")
+      .rule("S", &["S", "S"])
+      .rule("S", &["b"])
+      .into_grammar("S")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar);
+    let mut ev = EarleyForest::new(|_, _| 1u64);
+    ev.action("S -> S S", |n| n[0] * n[1]);
+    ev.action("S -> b", |n| n[0]);
+    let pout = p.parse(vec!["b"; 20].into_iter()).unwrap();
+    let count = ev.eval_sppf(&Sppf::new(&pout), |alts| alts.iter().sum());
+    // Catalan(19)
+    assert_eq!(count.unwrap(), 1767263190);
 }
 
 #[test]
@@ -319,11 +521,11 @@ fn natural_lang() {
     let grammar = GrammarBuilder::default()
       .terminal("N", |noun|
         vec!["flight", "banana", "time", "boy", "flies", "telescope"]
-        .contains(&noun))
-      .terminal("D", |det| vec!["the", "a", "an"].contains(&det))
-      .terminal("V", |verb| vec!["book", "eat", "sleep", "saw"].contains(&verb))
-      .terminal("P", |p| vec!["with", "in", "on", "at", "through"].contains(&p))
-      .terminal("[name]", |name| vec!["john", "houston"].contains(&name))
+        .contains(&noun.as_str()))
+      .terminal("D", |det| vec!["the", "a", "an"].contains(&det.as_str()))
+      .terminal("V", |verb| vec!["book", "eat", "sleep", "saw"].contains(&verb.as_str()))
+      .terminal("P", |p| vec!["with", "in", "on", "at", "through"].contains(&p.as_str()))
+      .terminal("[name]", |name| vec!["john", "houston"].contains(&name.as_str()))
       .nonterm("PP")
       .nonterm("NP")
       .nonterm("VP")
@@ -345,6 +547,286 @@ fn natural_lang() {
     ].iter().all(|input| p.parse(input.split_whitespace()).is_ok()));
 }
 
+#[test]
+fn typed_tokens() {
+    use parser::Error;
+    #[derive(Debug,PartialEq)]
+    enum Token { Num(f64), Op(char) }
+    // parse errors show tokens as they were in the input
+    impl fmt::Display for Token {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                Token::Num(n) => write!(f, "{}", n),
+                Token::Op(c) => write!(f, "{}", c),
+            }
+        }
+    }
+    // E -> E + n | n
+    let grammar = GrammarBuilder::typed()
+      .nonterm("E")
+      .terminal("n", |t: &Token| matches!(*t, Token::Num(_)))
+      .terminal("+", |t| *t == Token::Op('+'))
+      .rule("E", &["E", "+", "n"])
+      .rule("E", &["n"])
+      .into_grammar("E")
+      .expect("Bad grammar");
+    let mut evaler = EarleyForest::typed(|_, t: &Token| match *t {
+        Token::Num(n) => n,
+        Token::Op(_) => 0.0,
+    });
+    evaler.action("E -> E + n", |n| n[0] + n[2]);
+    evaler.action("E -> n", |n| n[0]);
+    let p = EarleyParser::new(grammar);
+    let input = vec![Token::Num(1.5), Token::Op('+'), Token::Num(2.0)];
+    assert_eq!(evaler.eval(&p.parse(input.into_iter()).unwrap()), Ok(3.5));
+    let input = vec![Token::Num(1.5), Token::Num(2.0)];
+    assert_eq!(p.parse(input.into_iter()).unwrap_err(), Error::ParseError{
+        index: 1, lexeme: Some("2".to_string()),
+        expected: vec!["+".to_string()]});
+}
+
+#[test]
+fn shared_across_threads() {
+    use std::sync::Arc;
+    use std::thread;
+    use trees::SyncEarleyForest;
+    // E -> E + n | n
+    let grammar = GrammarBuilder::default()
+      .nonterm("E")
+      .terminal("n", |n| n.chars().all(|c| c.is_ascii_digit()))
+      .terminal("+", |n| n == "+")
+      .rule("E", &["E", "+", "n"])
+      .rule("E", &["n"])
+      .into_grammar("E")
+      .expect("Bad grammar");
+    let mut evaler = SyncEarleyForest::new_sync(|_, n| n.parse().unwrap_or(0));
+    evaler.action("E -> E + n", |n| n[0] + n[2]);
+    evaler.action("E -> n", |n| n[0]);
+    let shared = Arc::new((EarleyParser::new(grammar), evaler));
+    let workers: Vec<_> = (1..5).map(|len| {
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let (ref parser, ref evaler) = *shared;
+            let input = vec!["2"; len].join(" + ");
+            evaler.eval(&parser.parse(input.split_whitespace()).unwrap())
+        })
+    }).collect();
+    let sums: Vec<_> = workers.into_iter()
+        .map(|w| w.join().unwrap().unwrap())
+        .collect();
+    assert_eq!(sums, vec![2, 4, 6, 8]);
+}
+
+#[test]
+fn weighted_best_trees() {
+    // PP attachment to verbs is more likely than to nouns
+    let grammar = GrammarBuilder::default()
+      .terminal("N", |noun| noun == "boy" || noun == "telescope")
+      .terminal("D", |det| det == "the" || det == "a")
+      .terminal("V", |verb| verb == "saw")
+      .terminal("P", |p| p == "with")
+      .terminal("[name]", |name| name == "john")
+      .nonterm("PP")
+      .nonterm("NP")
+      .nonterm("VP")
+      .nonterm("S")
+      .weighted_rule("NP", &["D", "N"], 0.5)
+      .weighted_rule("NP", &["[name]"], 0.3)
+      .weighted_rule("NP", &["NP", "PP"], 0.2)
+      .rule("PP", &["P", "NP"])
+      .weighted_rule("VP", &["V", "NP"], 0.6)
+      .weighted_rule("VP", &["VP", "PP"], 0.4)
+      .rule("S", &["NP", "VP"])
+      .into_grammar("S")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar.clone());
+    let ps = p.parse("john saw the boy with the telescope".split_whitespace())
+        .unwrap();
+    let mut evaler = EarleyForest::new(|_, tok| tok.to_string());
+    for rule in grammar.str_rules() {
+        evaler.action(&rule, |nodes| format!("({})", nodes.join(" ")));
+    }
+    let (best, weight) = evaler.eval_best(&ps).unwrap();
+    assert_eq!(best, "((john) ((saw (the boy)) (with (the telescope))))");
+    assert!((weight - 0.3 * 0.6 * 0.4 * 0.5 * 0.5).abs() < 1e-12);
+    // only 2 trees exist
+    let trees = evaler.eval_kbest(&ps, 5).unwrap();
+    assert_eq!(trees.len(), 2);
+    assert_eq!(trees[0].0, best);
+    assert_eq!(trees[1].0, "((john) (saw ((the boy) (with (the telescope)))))");
+    assert!((trees[1].1 - 0.3 * 0.6 * 0.2 * 0.5 * 0.5).abs() < 1e-12);
+}
+
+#[test]
+fn kbest_catalan() {
+    // S -> S S | b. All 14 trees of 'b b b b b' use the same rules
+    let grammar = GrammarBuilder::default()
+      .nonterm("S")
+      .terminal("b", |n| n == "b")
+      .weighted_rule("S", &["S", "S"], 0.5)
+      .weighted_rule("S", &["b"], 0.5)
+      .into_grammar("S")
+      .expect("Bad grammar");
+    let ps = EarleyParser::new(grammar.clone())
+        .parse("b b b b b".split_whitespace()).unwrap();
+    let evaler = tree_evaler(grammar);
+    let all = evaler.eval_kbest(&ps, 100).unwrap();
+    assert_eq!(all.len(), 14);
+    assert!(all.iter().all(|&(_, w)| (w - 0.5f64.powi(9)).abs() < 1e-12));
+    assert_eq!(evaler.eval_kbest(&ps, 3).unwrap().len(), 3);
+    assert!(evaler.eval_kbest(&ps, 0).unwrap().is_empty());
+}
+
+#[test]
+fn lazy_trees() {
+    // S -> S S | b
+    let grammar = GrammarBuilder::default()
+      .nonterm("S")
+      .terminal("b", |n| n == "b")
+      .rule("S", &["S", "S"])
+      .rule("S", &["b"])
+      .into_grammar("S")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar.clone());
+    let evaler = tree_evaler(grammar);
+    let ps = p.parse("b b b b b".split_whitespace()).unwrap();
+    let ordered: Vec<_> = evaler.eval_iter(&ps).ordered()
+        .map(|t| format!("{:?}", t.unwrap())).collect();
+    assert_eq!(ordered.len(), 14);
+    let again: Vec<_> = evaler.eval_iter(&ps).ordered()
+        .map(|t| format!("{:?}", t.unwrap())).collect();
+    assert_eq!(ordered, again);
+    let expected: Vec<_> = ordered.iter().map(|t| t.as_str()).collect();
+    check_trees(&evaler.eval_all(&ps).unwrap(), expected);
+    // Catalan(39) trees, only build the first few
+    let ps = p.parse(vec!["b"; 40].into_iter()).unwrap();
+    let first: Vec<_> = evaler.eval_iter(&ps).take(5)
+        .map(|t| format!("{:?}", t.unwrap())).collect();
+    assert_eq!(first.len(), 5);
+    assert!((1..5).all(|i| !first[..i].contains(&first[i])));
+}
+
+#[test]
+fn lazy_trees_cyclic() {
+    // A -> <empty> | B
+    // B -> A
+    let grammar = GrammarBuilder::default()
+      .nonterm("A")
+      .nonterm("B")
+      .rule::<_, &str>("A", &[])
+      .rule("A", &["B"])
+      .rule("B", &["A"])
+      .into_grammar("A")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar.clone());
+    let ps = p.parse("".split_whitespace()).unwrap();
+    // A -> <empty> and A -> B -> A -> <empty>, then derivations cycle
+    let trees: Vec<_> = tree_evaler(grammar).eval_iter(&ps).ordered()
+        .collect::<Result<_, _>>().unwrap();
+    assert_eq!(trees, vec![
+        Tree::Node("A -> ".to_string(), vec![]),
+        Tree::Node("A -> B".to_string(), vec![
+            Tree::Node("B -> A".to_string(), vec![
+                Tree::Node("A -> ".to_string(), vec![])])]),
+    ]);
+}
+
+#[test]
+fn rejecting_actions() {
+    // Date -> Day Month | Month Day
+    // Day -> n ; Month -> n
+    let grammar = GrammarBuilder::default()
+      .nonterm("Date")
+      .nonterm("Day")
+      .nonterm("Month")
+      .terminal("n", |n| n.chars().all(|c| c.is_ascii_digit()))
+      .rule("Date", &["Day", "Month"])
+      .rule("Date", &["Month", "Day"])
+      .rule("Day", &["n"])
+      .rule("Month", &["n"])
+      .into_grammar("Date")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar);
+    let mut ev = EarleyForest::new(|_, tok| tok.to_string());
+    ev.action("Date -> Day Month", |n| n.join(" "));
+    ev.action("Date -> Month Day", |n| n.join(" "));
+    ev.try_action("Day -> n", |n| n[0].parse::<u32>().ok()
+                  .filter(|d| (1..=31).contains(d))
+                  .map(|d| format!("day {}", d)));
+    ev.try_action("Month -> n", |n| match n[0].parse::<u32>() {
+        Ok(m) if (1..=12).contains(&m) => Ok(format!("month {}", m)),
+        _ => Err("not a month"),
+    });
+    let ps = p.parse("3 4".split_whitespace()).unwrap();
+    assert_eq!(ev.eval_all(&ps).unwrap().len(), 2);
+    // 13 can only be a day
+    let ps = p.parse("13 4".split_whitespace()).unwrap();
+    assert_eq!(ev.eval_all(&ps).unwrap(), vec!["day 13 month 4"]);
+    assert_eq!(ev.eval(&ps).unwrap(), "day 13 month 4");
+    assert_eq!(ev.eval_best(&ps).unwrap().0, "day 13 month 4");
+    assert_eq!(ev.eval_kbest(&ps, 5).unwrap().len(), 1);
+    let merged = ev.eval_sppf(&Sppf::new(&ps), |v| v.join("|")).unwrap();
+    assert_eq!(merged, "day 13 month 4");
+    // nothing is a valid date
+    let ps = p.parse("13 32".split_whitespace()).unwrap();
+    assert!(ev.eval_all(&ps).unwrap().is_empty());
+    assert_eq!(ev.eval(&ps).unwrap_err(), Error::Rejected("Date".to_string()));
+    assert_eq!(ev.eval_best(&ps).unwrap_err(),
+               Error::Rejected("Date".to_string()));
+    assert!(ev.eval_sppf(&Sppf::new(&ps), |v| v.join("|")).is_err());
+}
+
+#[test]
+fn kbest_rejected_subtrees() {
+    // S -> S S | X ; X -> b
+    // X is always rejected, so is every one of the Catalan(14) trees
+    let grammar = GrammarBuilder::default()
+      .nonterm("S")
+      .nonterm("X")
+      .terminal("b", |n| n == "b")
+      .rule("S", &["S", "S"])
+      .rule("S", &["X"])
+      .rule("X", &["b"])
+      .into_grammar("S")
+      .expect("Bad grammar");
+    let p = EarleyParser::new(grammar);
+    let mut ev = EarleyForest::new(|_, tok| tok.to_string());
+    ev.action("S -> S S", |n| n.concat());
+    ev.action("S -> X", |n| n.concat());
+    ev.try_action("X -> b", |_| None::<String>);
+    let ps = p.parse(vec!["b"; 15].into_iter()).unwrap();
+    assert_eq!(ev.eval_best(&ps).unwrap_err(), Error::Rejected("S".to_string()));
+    assert!(ev.eval_kbest(&ps, 10).unwrap().is_empty());
+}
+
+#[test]
+fn actions_by_rule_id() {
+    // Sum -> Sum + Num | Num ; Num -> n
+    let mut gb = GrammarBuilder::default()
+      .nonterm("Sum")
+      .nonterm("Num")
+      .terminal("n", |n| n.chars().all(|c| c.is_ascii_digit()))
+      .terminal("+", |n| n == "+");
+    let add = gb.add_rule("Sum", &["Sum", "+", "Num"]);
+    gb.add_rule("Sum", &["Num"]);
+    let num = gb.add_rule("Num", &["n"]);
+    let grammar = gb.into_grammar("Sum").expect("Bad grammar");
+
+    let mut ev = EarleyForest::new(|_, tok| tok.parse::<u32>().unwrap_or(0));
+    ev.action(add, |n| n[0] + n[2]);
+    ev.action(num, |n| n[0]);
+    // typo in the rule string is caught before parsing
+    ev.action("Sum ->  Num", |n| n[0]);
+    assert_eq!(ev.missing_actions(&grammar), vec!["Sum -> Num"]);
+    ev.action(ActionKey::Head("Sum".to_string()), |n| n[0]);
+    assert!(ev.missing_actions(&grammar).is_empty());
+
+    let p = EarleyParser::new(grammar);
+    let ps = p.parse("1 + 2 + 3".split_whitespace()).unwrap();
+    // rules with their own action don't use the head's fallback
+    assert_eq!(ev.eval(&ps).unwrap(), 6);
+}
+
 mod small_math {
     use grammar::{Grammar, GrammarBuilder};
     use parser::EarleyParser;
@@ -0,0 +1,251 @@
+#![deny(warnings)]
+
+use parser::{EarleyParser, ParseSession, ParseTrees, Error};
+use std::cmp::Reverse;
+use std::fmt;
+use std::collections::{BinaryHeap, HashMap};
+use std::collections::hash_map::Entry;
+
+
+// Token edits assumed when repairing input that doesn't parse
+#[derive(Clone,Debug,PartialEq)]
+pub enum Edit<T = String> {
+    // token at the index of the input was ignored
+    Delete(usize, T),
+    // token was inserted before the index of the input
+    Insert(usize, T),
+}
+
+// Bounds and costs of the edits tried to repair input
+#[derive(Clone,Debug)]
+pub struct Recovery<T = String> {
+    max_edits: usize,
+    delete_cost: u32,
+    inserts: Vec<(T, u32)>,
+}
+
+#[derive(Debug)]
+pub struct Repaired<T = String> {
+    pub trees: ParseTrees<T>,
+    pub edits: Vec<Edit<T>>,
+    pub cost: u32,
+}
+
+// A partial parse and the edits that led to it
+type Candidate<'a, T> = (ParseSession<'a, T>, Vec<Edit<T>>);
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl<T> Recovery<T> {
+    // By default tokens can only be deleted at a cost of 1
+    pub fn new(max_edits: usize) -> Recovery<T> {
+        Recovery{max_edits, delete_cost: 1, inserts: Vec::new()}
+    }
+
+    pub fn delete_cost(mut self, cost: u32) -> Self {
+        self.delete_cost = cost;
+        self
+    }
+
+    // Allow inserting 'token' where the input is missing it
+    pub fn insert<S: Into<T>>(mut self, token: S, cost: u32) -> Self {
+        self.inserts.push((token.into(), cost));
+        self
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl<T: Clone + fmt::Display> EarleyParser<T> {
+    pub fn with_recovery(mut self, recovery: Recovery<T>) -> Self {
+        self.recovery = Some(recovery);
+        self
+    }
+
+    // Parse input allowing a bounded number of edits to make it valid.
+    // Edits start where the input fails to parse, the tokens before it are
+    // kept. Returns the cheapest repair found or the error of the input.
+    pub fn parse_recover<S, SI>(&self, tok: SI) -> Result<Repaired<T>, Error>
+            where S: Into<T>, SI: Iterator<Item=S> {
+        let tokens: Vec<T> = tok.map(|t| t.into()).collect();
+        let mut session = self.session();
+        let mut start = 0;
+        let error = loop {
+            let pushed = match tokens.get(start) {
+                Some(token) => session.push(token.clone()),
+                None => match session.parse_trees() {
+                    Ok(trees) =>
+                        return Ok(Repaired{trees, edits: Vec::new(), cost: 0}),
+                    Err(e) => break e,
+                },
+            };
+            match pushed {
+                Ok(()) => start += 1,
+                Err(e) => break e,
+            }
+        };
+        let recovery = match self.recovery {
+            Some(ref recovery) => recovery,
+            None => return Err(error),
+        };
+
+        // Uniform cost search over partial parses. Heap entries point into
+        // 'nodes', ties are broken in favor of the most advanced parse.
+        // Parses with the same StateSets since 'start' accept the same input,
+        // only the cheapest one (for a number of edits) is searched.
+        let mut visited = HashMap::new();
+        let mut nodes: Vec<Option<Candidate<T>>> = Vec::new();
+        let mut heap = BinaryHeap::new();
+        nodes.push(Some((session, Vec::new())));
+        heap.push((Reverse(0), start, 0));
+
+        while let Some((Reverse(cost), pos, id)) = heap.pop() {
+            let (session, edits) = nodes[id].take().expect("BUG: node visited");
+            if pos == tokens.len() && session.is_complete() {
+                return Ok(Repaired{trees: session.parse_trees()?, edits, cost});
+            }
+            let mut next = Vec::new();
+            if pos < tokens.len() {
+                let mut accepted = session.clone();
+                if accepted.push(tokens[pos].clone()).is_ok() {
+                    next.push((cost, pos + 1, accepted, edits.clone()));
+                }
+            }
+            if edits.len() < recovery.max_edits {
+                if pos < tokens.len() {
+                    let mut edits = edits.clone();
+                    edits.push(Edit::Delete(pos, tokens[pos].clone()));
+                    next.push((cost + recovery.delete_cost, pos + 1,
+                               session.clone(), edits));
+                }
+                for &(ref token, insert_cost) in &recovery.inserts {
+                    let mut inserted = session.clone();
+                    if inserted.push(token.clone()).is_ok() {
+                        let mut edits = edits.clone();
+                        edits.push(Edit::Insert(pos, token.clone()));
+                        next.push((cost + insert_cost, pos, inserted, edits));
+                    }
+                }
+            }
+            for (cost, pos, session, edits) in next {
+                let states = session.states_since(start).to_vec();
+                match visited.entry((pos, edits.len(), states)) {
+                    Entry::Occupied(ref best) if *best.get() <= cost => continue,
+                    Entry::Occupied(mut best) => { best.insert(cost); },
+                    Entry::Vacant(slot) => { slot.insert(cost); },
+                }
+                nodes.push(Some((session, edits)));
+                heap.push((Reverse(cost), pos, nodes.len() - 1));
+            }
+        }
+        Err(error)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::{Grammar, GrammarBuilder};
+    use parser::{EarleyParser, Error};
+    use super::{Recovery, Edit};
+
+    fn grammar() -> Grammar {
+        // Sum -> Sum + Num | Num
+        // Num -> n | ( Sum )
+        GrammarBuilder::default()
+          .nonterm("Sum")
+          .nonterm("Num")
+          .terminal("n", |n| n.chars().all(|c| "1234".contains(c)))
+          .terminal("+", |n| n == "+")
+          .terminal("(", |n| n == "(")
+          .terminal(")", |n| n == ")")
+          .rule("Sum", &["Sum", "+", "Num"])
+          .rule("Sum", &["Num"])
+          .rule("Num", &["n"])
+          .rule("Num", &["(", "Sum", ")"])
+          .into_grammar("Sum")
+          .expect("Bad grammar")
+    }
+
+    #[test]
+    fn valid_input() {
+        let p = EarleyParser::new(grammar()).with_recovery(Recovery::new(2));
+        let repaired = p.parse_recover("1 + 2".split_whitespace()).unwrap();
+        assert_eq!(repaired.cost, 0);
+        assert!(repaired.edits.is_empty());
+    }
+
+    #[test]
+    fn no_recovery() {
+        let p = EarleyParser::new(grammar());
+        match p.parse_recover("1 + + 2".split_whitespace()) {
+            Err(Error::ParseError{index: 2, ..}) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delete_tokens() {
+        let p = EarleyParser::new(grammar()).with_recovery(Recovery::new(2));
+        let repaired = p.parse_recover("1 + + 2".split_whitespace()).unwrap();
+        assert_eq!(repaired.cost, 1);
+        assert_eq!(repaired.edits.len(), 1);
+        match repaired.edits[0] {
+            Edit::Delete(idx, ref token) =>
+                assert!((idx == 1 || idx == 2) && token == "+"),
+            ref edit => panic!("Unexpected {:?}", edit),
+        }
+        let repaired = p.parse_recover(") 1 + 2 )".split_whitespace()).unwrap();
+        assert_eq!(repaired.edits, vec![Edit::Delete(0, ")".to_string()),
+                                        Edit::Delete(4, ")".to_string())]);
+        // too many edits needed
+        assert!(p.parse_recover(") ) 1 + 2 )".split_whitespace()).is_err());
+    }
+
+    #[test]
+    fn insert_tokens() {
+        let recovery = Recovery::new(3)
+            .delete_cost(4)
+            .insert(")", 1)
+            .insert("1", 2);
+        let p = EarleyParser::new(grammar()).with_recovery(recovery);
+        let repaired = p.parse_recover("( ( 2".split_whitespace()).unwrap();
+        assert_eq!(repaired.cost, 2);
+        assert_eq!(repaired.edits, vec![Edit::Insert(3, ")".to_string()),
+                                        Edit::Insert(3, ")".to_string())]);
+        let repaired = p.parse_recover("1 + ".split_whitespace()).unwrap();
+        assert_eq!(repaired.edits, vec![Edit::Insert(2, "1".to_string())]);
+        let repaired = p.parse_recover("1 + (".split_whitespace()).unwrap();
+        assert_eq!(repaired.cost, 3);
+        assert_eq!(repaired.edits, vec![Edit::Insert(3, "1".to_string()),
+                                        Edit::Insert(3, ")".to_string())]);
+        // no insertion helps, pay for deleting instead
+        let repaired = p.parse_recover("1 + 2 ) )".split_whitespace()).unwrap();
+        assert_eq!(repaired.cost, 8);
+        assert_eq!(repaired.edits, vec![Edit::Delete(3, ")".to_string()),
+                                        Edit::Delete(4, ")".to_string())]);
+    }
+    #[test]
+    fn late_error() {
+        // L -> L , x | x
+        let grammar = GrammarBuilder::default()
+          .nonterm("L")
+          .terminal("x", |n| n == "x")
+          .terminal(",", |n| n == ",")
+          .rule("L", &["L", ",", "x"])
+          .rule("L", &["x"])
+          .into_grammar("L")
+          .expect("Bad grammar");
+        let recovery = Recovery::new(3).delete_cost(2).insert(",", 1);
+        let p = EarleyParser::new(grammar).with_recovery(recovery);
+        // repairs are only searched from the error on
+        let mut input = vec!["x"; 500].join(" , ");
+        let repaired = p.parse_recover(
+            (input.clone() + " x").split_whitespace()).unwrap();
+        assert_eq!(repaired.edits, vec![Edit::Insert(999, ",".to_string())]);
+        // every repair with up to 3 edits fails
+        input.push_str(" y y y y");
+        assert!(p.parse_recover(input.split_whitespace()).is_err());
+    }
+}
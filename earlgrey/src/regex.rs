@@ -0,0 +1,441 @@
+#![deny(warnings)]
+
+use parser::Error;
+use std::iter::Peekable;
+use std::str::Chars;
+
+
+// Regex subset for terminals: literals, '.', classes ([a-z_], [^0-9], \d \w
+// \s and their negations), groups, alternation, repetition (* + ? {n} {n,}
+// {n,m}) and anchors (^ $). Patterns match whole tokens, ie: "[0-9]+"
+// doesn't match "12a". They're compiled to a program of instructions and
+// run over every thread at once (Thompson's NFA), no backtracking.
+#[derive(Clone,Debug)]
+pub struct Regex {
+    program: Vec<Inst>,
+}
+
+#[derive(Clone,Debug,PartialEq)]
+struct Class {
+    negated: bool,
+    ranges: Vec<(char, char)>,
+}
+
+#[derive(Clone,Debug)]
+enum Node {
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat(Box<Node>, usize, Option<usize>),
+}
+
+#[derive(Clone,Debug)]
+enum Inst {
+    // consume a char in the class and go to the next instruction
+    Class(Class),
+    // try both instructions
+    Split(usize, usize),
+    Jump(usize),
+    Start,
+    End,
+    Match,
+}
+
+// {n,m} copies the repeated pattern, bounded to keep programs small.
+// Nested repetitions multiply the copies, so the program is bounded too
+// (even copies of empty patterns, they take as long to compile).
+const MAX_REPEAT: usize = 1000;
+const MAX_PROGRAM: usize = 10_000;
+
+struct Parser<'p> {
+    chars: Peekable<Chars<'p>>,
+    pattern: &'p str,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl Class {
+    fn of(ranges: &[(char, char)]) -> Class {
+        Class{negated: false, ranges: ranges.to_vec()}
+    }
+
+    fn negate(mut self) -> Class {
+        self.negated = !self.negated;
+        self
+    }
+
+    fn matches(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi) != self.negated
+    }
+}
+
+impl<'p> Parser<'p> {
+    fn error(&self, reason: &str) -> Error {
+        Error::InvalidRegex(format!("{} ({})", self.pattern, reason))
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.chars.peek() == Some(&c) {
+            self.chars.next();
+            return true;
+        }
+        false
+    }
+
+    // alt := concat ('|' concat)*
+    fn alt(&mut self) -> Result<Node, Error> {
+        let mut alts = vec![self.concat()?];
+        while self.eat('|') {
+            alts.push(self.concat()?);
+        }
+        Ok(if alts.len() == 1 { alts.remove(0) } else { Node::Alt(alts) })
+    }
+
+    // concat := repeat*
+    fn concat(&mut self) -> Result<Node, Error> {
+        let mut nodes = Vec::new();
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' { break; }
+            nodes.push(self.repeat()?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    // repeat := atom ('*' | '+' | '?' | '{' n [',' [m]] '}')*
+    fn repeat(&mut self) -> Result<Node, Error> {
+        let mut node = self.atom()?;
+        loop {
+            let (min, max) = match self.chars.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.chars.next();
+                    let min = self.number()?;
+                    let max = if self.eat(',') {
+                        if self.chars.peek() == Some(&'}') { None }
+                        else { Some(self.number()?) }
+                    } else {
+                        Some(min)
+                    };
+                    if !self.eat('}') { return Err(self.error("unclosed {")); }
+                    if matches!(max, Some(max) if max < min) {
+                        return Err(self.error("bad repetition range"));
+                    }
+                    if max.unwrap_or(min) > MAX_REPEAT {
+                        return Err(self.error("repetition too large"));
+                    }
+                    node = Node::Repeat(Box::new(node), min, max);
+                    continue;
+                },
+                _ => return Ok(node),
+            };
+            self.chars.next();
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    fn number(&mut self) -> Result<usize, Error> {
+        let mut digits = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_digit() { break; }
+            digits.push(c);
+            self.chars.next();
+        }
+        digits.parse().map_err(|_| self.error("expected a number"))
+    }
+
+    fn atom(&mut self) -> Result<Node, Error> {
+        match self.chars.next() {
+            Some('(') => {
+                let node = self.alt()?;
+                if !self.eat(')') { return Err(self.error("unclosed group")); }
+                Ok(node)
+            },
+            Some('[') => self.class().map(Node::Class),
+            Some('.') => Ok(Node::Class(Class::of(&[]).negate())),
+            Some('^') => Ok(Node::Start),
+            Some('$') => Ok(Node::End),
+            Some('\\') => self.escape().map(Node::Class),
+            Some(c) if "*+?{".contains(c) =>
+                Err(self.error("nothing to repeat")),
+            Some(c) => Ok(Node::Class(Class::of(&[(c, c)]))),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+
+    // Escaped char after a '\', either a class or a literal char
+    fn escape(&mut self) -> Result<Class, Error> {
+        let digit = Class::of(&[('0', '9')]);
+        let word = Class::of(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]);
+        let space = Class::of(&[(' ', ' '), ('\t', '\r')]);
+        Ok(match self.chars.next() {
+            Some('d') => digit,
+            Some('w') => word,
+            Some('s') => space,
+            Some('D') => digit.negate(),
+            Some('W') => word.negate(),
+            Some('S') => space.negate(),
+            Some('n') => Class::of(&[('\n', '\n')]),
+            Some('t') => Class::of(&[('\t', '\t')]),
+            Some(c) if !c.is_alphanumeric() => Class::of(&[(c, c)]),
+            Some(_) => return Err(self.error("unknown escape")),
+            None => return Err(self.error("trailing \\")),
+        })
+    }
+
+    // [abc] [a-z] [^0-9] after the '[', with \d \w \s and escaped chars
+    fn class(&mut self) -> Result<Class, Error> {
+        let negated = self.eat('^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+        loop {
+            let lo = match self.chars.next() {
+                Some(']') if !first => break,
+                Some('\\') => {
+                    let class = self.escape()?;
+                    if class.negated {
+                        return Err(self.error("negated class in []"));
+                    }
+                    match class.ranges[..] {
+                        [(c, d)] if c == d => c,
+                        _ => {
+                            ranges.extend(class.ranges);
+                            first = false;
+                            continue;
+                        },
+                    }
+                },
+                Some(c) => c,
+                None => return Err(self.error("unclosed [")),
+            };
+            first = false;
+            let mut ahead = self.chars.clone();
+            if ahead.next() == Some('-') &&
+                    matches!(ahead.peek(), Some(&c) if c != ']') {
+                self.chars.next();
+                let hi = match self.chars.next() {
+                    Some('\\') => {
+                        let class = self.escape()?;
+                        match class.ranges[..] {
+                            [(c, d)] if c == d && !class.negated => c,
+                            _ => return Err(self.error("bad class range")),
+                        }
+                    },
+                    Some(c) => c,
+                    None => return Err(self.error("unclosed [")),
+                };
+                if hi < lo { return Err(self.error("bad class range")); }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Class{negated, ranges})
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Regex, Error> {
+        let mut parser = Parser{chars: pattern.chars().peekable(), pattern};
+        let node = parser.alt()?;
+        if parser.chars.next().is_some() {
+            return Err(parser.error("unmatched )"));
+        }
+        if Regex::size(&node) > MAX_PROGRAM {
+            return Err(parser.error("program too large"));
+        }
+        let mut program = Vec::new();
+        Regex::compile(&node, &mut program);
+        program.push(Inst::Match);
+        Ok(Regex{program})
+    }
+
+    // Upper bound of the instructions compiled for 'node', nodes that
+    // compile to none (eg: empty groups) still count as one
+    fn size(node: &Node) -> usize {
+        match *node {
+            Node::Class(_) | Node::Start | Node::End => 1,
+            Node::Concat(ref nodes) => nodes.iter()
+                .fold(1, |size, node| size.saturating_add(Regex::size(node))),
+            Node::Alt(ref alts) => alts.iter()
+                .fold(1, |size, alt| size.saturating_add(Regex::size(alt) + 2)),
+            // each copy may add a split, loops a jump back too
+            Node::Repeat(ref node, min, max) => {
+                let copies = max.unwrap_or(min) + 1;
+                (Regex::size(node).saturating_add(2)).saturating_mul(copies)
+            },
+        }
+    }
+
+    fn compile(node: &Node, program: &mut Vec<Inst>) {
+        match *node {
+            Node::Class(ref class) => program.push(Inst::Class(class.clone())),
+            Node::Start => program.push(Inst::Start),
+            Node::End => program.push(Inst::End),
+            Node::Concat(ref nodes) => {
+                for node in nodes { Regex::compile(node, program); }
+            },
+            Node::Alt(ref alts) => {
+                // split to each alternative, each one jumps past the rest
+                let mut jumps = Vec::new();
+                for (idx, alt) in alts.iter().enumerate() {
+                    let split = program.len();
+                    if idx + 1 < alts.len() { program.push(Inst::Split(0, 0)); }
+                    Regex::compile(alt, program);
+                    if idx + 1 < alts.len() {
+                        jumps.push(program.len());
+                        program.push(Inst::Jump(0));
+                        program[split] = Inst::Split(split + 1, program.len());
+                    }
+                }
+                for jump in jumps { program[jump] = Inst::Jump(program.len()); }
+            },
+            Node::Repeat(ref node, min, max) => {
+                for _ in 0..min { Regex::compile(node, program); }
+                match max {
+                    // loop: split into the node or past it, node jumps back
+                    None => {
+                        let split = program.len();
+                        program.push(Inst::Split(0, 0));
+                        Regex::compile(node, program);
+                        program.push(Inst::Jump(split));
+                        program[split] = Inst::Split(split + 1, program.len());
+                    },
+                    // optional copies, skipping one skips the rest
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in min..max {
+                            splits.push(program.len());
+                            program.push(Inst::Split(0, 0));
+                            Regex::compile(node, program);
+                        }
+                        for split in splits {
+                            program[split] = Inst::Split(split + 1, program.len());
+                        }
+                    },
+                }
+            },
+        }
+    }
+
+    // Follow instructions that don't consume chars from 'pc'
+    fn add_thread(&self, threads: &mut Vec<usize>, seen: &mut Vec<bool>,
+                  pc: usize, at_start: bool, at_end: bool) {
+        if seen[pc] { return; }
+        seen[pc] = true;
+        match self.program[pc] {
+            Inst::Split(a, b) => {
+                self.add_thread(threads, seen, a, at_start, at_end);
+                self.add_thread(threads, seen, b, at_start, at_end);
+            },
+            Inst::Jump(a) => self.add_thread(threads, seen, a, at_start, at_end),
+            Inst::Start if at_start =>
+                self.add_thread(threads, seen, pc + 1, at_start, at_end),
+            Inst::End if at_end =>
+                self.add_thread(threads, seen, pc + 1, at_start, at_end),
+            Inst::Start | Inst::End => (),
+            Inst::Class(_) | Inst::Match => threads.push(pc),
+        }
+    }
+
+    // Check if the whole of 'text' matches
+    pub fn is_match(&self, text: &str) -> bool {
+        let len = text.chars().count();
+        let mut threads = Vec::new();
+        let mut seen = vec![false; self.program.len()];
+        self.add_thread(&mut threads, &mut seen, 0, true, len == 0);
+        for (idx, c) in text.chars().enumerate() {
+            let mut next = Vec::new();
+            let mut seen = vec![false; self.program.len()];
+            for &pc in &threads {
+                if let Inst::Class(ref class) = self.program[pc] {
+                    if class.matches(c) {
+                        self.add_thread(&mut next, &mut seen, pc + 1,
+                                        false, idx + 1 == len);
+                    }
+                }
+            }
+            if next.is_empty() { return false; }
+            threads = next;
+        }
+        threads.iter().any(|&pc| matches!(self.program[pc], Inst::Match))
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::GrammarBuilder;
+    use parser::{EarleyParser, Error};
+    use super::Regex;
+
+    fn matches(pattern: &str, texts: &[&str]) -> Vec<bool> {
+        let re = Regex::new(pattern).unwrap();
+        texts.iter().map(|t| re.is_match(t)).collect()
+    }
+
+    #[test]
+    fn regex_match() {
+        assert_eq!(matches("[0-9]+", &["123", "", "12a", "7"]),
+                   vec![true, false, false, true]);
+        assert_eq!(matches("-?\\d+(\\.\\d*)?", &["-1.5", "3.", ".5", "42"]),
+                   vec![true, true, false, true]);
+        assert_eq!(matches("mon(day)?|tue(sday)?",
+                           &["mon", "tuesday", "tues"]),
+                   vec![true, true, false]);
+        assert_eq!(matches("[^a-c_]\\w{2,3}", &["dab", "a12", "x1234", "z__"]),
+                   vec![true, false, false, true]);
+        assert_eq!(matches("a{3}|b{2,}", &["aaa", "aa", "bb", "bbbbb"]),
+                   vec![true, false, true, true]);
+        assert_eq!(matches("(a|b)*c", &["c", "ababc", "abca"]),
+                   vec![true, true, false]);
+        assert_eq!(matches("^x$|^$", &["x", "", "xx"]),
+                   vec![true, true, false]);
+        assert_eq!(matches("a^b", &["ab"]), vec![false]);
+        assert_eq!(matches("[-+*/]|.", &["+", "-", "\u{e9}", "ab"]),
+                   vec![true, true, true, false]);
+        assert_eq!(matches("\\s*\\S", &[" \tx", "  "]), vec![true, false]);
+    }
+
+    #[test]
+    fn regex_errors() {
+        for &pattern in &["(ab", "ab)", "[a-", "[z-a]", "*a", "a{2,1}",
+                          "a{5000}", "((a{1000}){1000}){1000}",
+                          "(((){1000}){1000}){1000}", "\\q", "a\\"] {
+            match Regex::new(pattern) {
+                Err(Error::InvalidRegex(msg)) =>
+                    assert!(msg.starts_with(pattern)),
+                r => panic!("{}: {:?}", pattern, r),
+            }
+        }
+    }
+
+    #[test]
+    fn regex_terminals() {
+        // Sum -> Sum + Num | Num
+        let grammar = GrammarBuilder::default()
+          .nonterm("Sum")
+          .terminal_re("Num", "[0-9]+")
+          .terminal_re("+", "\\+")
+          .rule("Sum", &["Sum", "+", "Num"])
+          .rule("Sum", &["Num"])
+          .into_grammar("Sum")
+          .unwrap();
+        let p = EarleyParser::new(grammar);
+        assert!(p.parse("12 + 3".split_whitespace()).is_ok());
+        assert!(p.parse("12 + 3a".split_whitespace()).is_err());
+        let grammar = GrammarBuilder::default()
+          .nonterm("Sum")
+          .terminal_re("Num", "[0-9")
+          .rule("Sum", &["Num"])
+          .into_grammar("Sum");
+        assert_eq!(grammar.unwrap_err(),
+                   Error::InvalidRegex("[0-9 (unclosed [)".to_string()));
+    }
+}
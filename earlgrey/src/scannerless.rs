@@ -0,0 +1,173 @@
+#![deny(warnings)]
+
+use grammar::{Grammar, GrammarBuilder, Rule, Symbol};
+use parser::{EarleyParser, ParseTrees, Span, Error};
+use trees::{EarleyForest, SyncEarleyForest};
+use std::iter;
+
+
+// Scannerless grammars parse &str input one char at a time, they're
+// Grammar<char> built with char classes and literal strings as terminals.
+// Positions reported by the *_str methods are byte offsets into the input.
+
+// Byte offset of each char of 'input' and of its end
+fn byte_offsets(input: &str) -> Vec<usize> {
+    input.char_indices().map(|(offset, _)| offset)
+        .chain(iter::once(input.len()))
+        .collect()
+}
+
+// Literals are rules named after their text made of one terminal per char
+fn is_literal<T>(rule: &Rule<T>) -> bool {
+    rule.spec.len() == rule.head.chars().count() &&
+        rule.spec.iter().zip(rule.head.chars()).all(|(s, c)| match **s {
+            Symbol::Terminal(ref name, _) => *name == format!("{:?}", c),
+            Symbol::NonTerm(_) => false,
+        })
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl GrammarBuilder<char> {
+    // Terminal matching any char of 'set'
+    pub fn chars<S: Into<String>>(self, name: S, set: &str) -> Self {
+        let set = set.to_string();
+        self.terminal(name, move |c: &char| set.contains(*c))
+    }
+
+    // Terminal matching chars from 'lo' to 'hi' (inclusive)
+    pub fn char_range<S: Into<String>>(self, name: S, lo: char, hi: char)
+            -> Self {
+        self.terminal(name, move |c: &char| lo <= *c && *c <= hi)
+    }
+
+    // Non-terminal named 'text' matching it literally (eg: "let"). Each
+    // char is a terminal named by its debug format ('l') shared by literals.
+    pub fn literal(mut self, text: &str) -> Self {
+        let chars: Vec<String> =
+            text.chars().map(|c| format!("{:?}", c)).collect();
+        for (name, c) in chars.iter().zip(text.chars()) {
+            self.add_terminal(name.as_str(), move |t: &char| *t == c, true);
+        }
+        self.add_nonterm(text, false);
+        self.add_rule(text, &chars);
+        self
+    }
+}
+
+impl EarleyParser<char> {
+    // Parse errors point at the byte offset of the unexpected char
+    pub fn parse_str(&self, input: &str) -> Result<ParseTrees<char>, Error> {
+        self.parse(input.chars()).map_err(|e| match e {
+            Error::ParseError{index, lexeme, expected} => Error::ParseError{
+                index: byte_offsets(input)[index], lexeme, expected},
+            e => e,
+        })
+    }
+
+    // Spans of 'input' recognized as 'symbol', &input[start..end] is the
+    // text of each span
+    pub fn parse_str_spans(&self, symbol: &str, input: &str)
+            -> Result<Vec<Span<char>>, Error> {
+        let offsets = byte_offsets(input);
+        let spans = self.parse_spans(symbol, input.chars())?;
+        Ok(spans.into_iter()
+           .map(|span| Span{start: offsets[span.start],
+                            end: offsets[span.end], trees: span.trees})
+           .collect())
+    }
+}
+
+impl<'a, ASTNode: Clone + 'a> EarleyForest<'a, ASTNode, char> {
+    // Actions for every literal of 'grammar' building a node from its text
+    pub fn literals<F>(&mut self, grammar: &Grammar<char>, text: F)
+            where F: Fn(&str) -> ASTNode + Clone + 'a {
+        for rule in grammar.rules.iter().filter(|r| is_literal(r)) {
+            let (text, literal) = (text.clone(), rule.head.clone());
+            self.action(rule.id, move |_| text(&literal));
+        }
+    }
+}
+
+impl<'a, ASTNode: Clone + 'a> SyncEarleyForest<'a, ASTNode, char> {
+    pub fn literals<F>(&mut self, grammar: &Grammar<char>, text: F)
+            where F: Fn(&str) -> ASTNode + Clone + Send + Sync + 'a {
+        for rule in grammar.rules.iter().filter(|r| is_literal(r)) {
+            let (text, literal) = (text.clone(), rule.head.clone());
+            self.action(rule.id, move |_| text(&literal));
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::{Grammar, GrammarBuilder};
+    use parser::{EarleyParser, Error};
+    use trees::{EarleyForest, ActionKey};
+
+    fn grammar() -> Grammar<char> {
+        // Stmt -> let Ws Id OWs = OWs Num ; Ws -> ws | Ws ws ; OWs -> Ws | <e>
+        // Id -> alpha | Id alpha ; Num -> digit | Num digit
+        GrammarBuilder::typed()
+          .nonterm("Stmt")
+          .nonterm("Ws")
+          .nonterm("OWs")
+          .nonterm("Id")
+          .nonterm("Num")
+          .chars("ws", " \t\u{a0}")
+          .char_range("alpha", 'a', 'z')
+          .char_range("digit", '0', '9')
+          .literal("let")
+          .literal("=")
+          .rule("Stmt", &["let", "Ws", "Id", "OWs", "=", "OWs", "Num"])
+          .rule("Ws", &["ws"])
+          .rule("Ws", &["Ws", "ws"])
+          .rule("OWs", &["Ws"])
+          .rule::<_, &str>("OWs", &[])
+          .rule("Id", &["alpha"])
+          .rule("Id", &["Id", "alpha"])
+          .rule("Num", &["digit"])
+          .rule("Num", &["Num", "digit"])
+          .into_grammar("Stmt")
+          .expect("Bad grammar")
+    }
+
+    #[test]
+    fn scannerless() {
+        let grammar = grammar();
+        let mut ev = EarleyForest::typed(|_, c: &char| c.to_string());
+        ev.literals(&grammar, |text| text.to_string());
+        for head in &["Ws", "OWs", "Id", "Num"] {
+            ev.action(ActionKey::Head(head.to_string()), |n| n.concat());
+        }
+        ev.action("Stmt -> let Ws Id OWs = OWs Num", |n| n.concat());
+        assert!(ev.missing_actions(&grammar).is_empty());
+        let p = EarleyParser::new(grammar);
+        let ptrees = p.parse_str("let  answer=42").unwrap();
+        assert_eq!(ev.eval(&ptrees).unwrap(), "let  answer=42");
+        // 'l' is shared by the literal and the alpha char class
+        assert!(p.parse_str("let let = 1").is_ok());
+
+        // \u{a0} takes 2 bytes, errors are at byte offsets
+        match p.parse_str("let\u{a0}x = ?").unwrap_err() {
+            Error::ParseError{index, lexeme, ..} => {
+                assert_eq!(index, 9);
+                assert_eq!(lexeme, Some("?".to_string()));
+            },
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn str_spans() {
+        let p = EarleyParser::new(grammar());
+        let input = "x 12 \u{e9} 345";
+        let spans: Vec<_> = p.parse_str_spans("Num", input).unwrap()
+            .into_iter()
+            .map(|span| &input[span.start..span.end])
+            .collect();
+        assert_eq!(spans, vec!["1", "12", "2", "3", "34", "345", "4", "45", "5"]);
+    }
+}
@@ -0,0 +1,390 @@
+#![deny(warnings)]
+
+use filters::{Assoc, Filter};
+use grammar::{Grammar, GrammarBuilder, Symbol};
+use parser::Error;
+use templates::Template;
+use std::collections::HashMap;
+
+
+// Binary format of a grammar, integers are little endian and strings are
+// their length (u32) followed by utf-8 bytes:
+//   magic "EGRM", version (u8), start symbol
+//   symbols: count (u32), each a kind (0 non-terminal, 1 terminal) and name
+//   rules: count (u32), each a head, spec length (u32), index of each spec
+//          symbol (u32) and weight (f64 bits as u64)
+//   filters: count (u32), each a kind and rules (as strings) in lists
+//            (count (u32) and each rule) or alone:
+//              0 priority: higher list, lower list
+//              1 associativity: 0 left, 1 right or 2 non (u8) and list
+//              2 reject, 3 prefer: rule
+//   templates: count (u32), each the symbol of the instance, a kind
+//              (0 many, 1 many1, 2 optional, 3 sep_by, 4 sep_by1) and its
+//              item (plus separator for sep_by and sep_by1)
+// Terminal predicates can't be serialized, they're referenced by name.
+const MAGIC: &[u8] = b"EGRM";
+const VERSION: u8 = 3;
+
+const NONTERM: u8 = 0;
+const TERMINAL: u8 = 1;
+
+const PRIORITY: u8 = 0;
+const ASSOC: u8 = 1;
+const REJECT: u8 = 2;
+const PREFER: u8 = 3;
+
+const MANY: u8 = 0;
+const MANY1: u8 = 1;
+const OPTIONAL: u8 = 2;
+const SEP_BY: u8 = 3;
+const SEP_BY1: u8 = 4;
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+}
+
+fn invalid(what: &str) -> Error { Error::InvalidData(what.to_string()) }
+
+fn put_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn put_strs(out: &mut Vec<u8>, strs: &[String]) {
+    put_u32(out, strs.len());
+    for s in strs { put_str(out, s); }
+}
+
+fn put_filter(out: &mut Vec<u8>, filter: &Filter) {
+    match *filter {
+        Filter::Priority(ref higher, ref lower) => {
+            out.push(PRIORITY);
+            put_strs(out, higher);
+            put_strs(out, lower);
+        },
+        Filter::Assoc(assoc, ref group) => {
+            out.push(ASSOC);
+            out.push(match assoc {
+                Assoc::Left => 0,
+                Assoc::Right => 1,
+                Assoc::Non => 2,
+            });
+            put_strs(out, group);
+        },
+        Filter::Reject(ref rule) => {
+            out.push(REJECT);
+            put_str(out, rule);
+        },
+        Filter::Prefer(ref rule) => {
+            out.push(PREFER);
+            put_str(out, rule);
+        },
+    }
+}
+
+fn put_template(out: &mut Vec<u8>, template: &Template) {
+    match *template {
+        Template::Many(ref x) => { out.push(MANY); put_str(out, x); },
+        Template::Many1(ref x) => { out.push(MANY1); put_str(out, x); },
+        Template::Optional(ref x) => { out.push(OPTIONAL); put_str(out, x); },
+        Template::SepBy(ref x, ref sep) => {
+            out.push(SEP_BY);
+            put_str(out, x);
+            put_str(out, sep);
+        },
+        Template::SepBy1(ref x, ref sep) => {
+            out.push(SEP_BY1);
+            put_str(out, x);
+            put_str(out, sep);
+        },
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], Error> {
+        if self.bytes.len() - self.pos < n {
+            return Err(invalid("truncated"));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> { Ok(self.take(1)?[0]) }
+
+    fn u32(&mut self) -> Result<usize, Error> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(buf) as usize)
+    }
+
+    fn f64(&mut self) -> Result<f64, Error> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(buf)))
+    }
+
+    fn str(&mut self) -> Result<String, Error> {
+        let len = self.u32()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| invalid("bad utf-8 string"))
+    }
+
+    fn strs(&mut self) -> Result<Vec<String>, Error> {
+        (0..self.u32()?).map(|_| self.str()).collect()
+    }
+
+    fn filter(&mut self) -> Result<Filter, Error> {
+        Ok(match self.u8()? {
+            PRIORITY => Filter::Priority(self.strs()?, self.strs()?),
+            ASSOC => {
+                let assoc = match self.u8()? {
+                    0 => Assoc::Left,
+                    1 => Assoc::Right,
+                    2 => Assoc::Non,
+                    _ => return Err(invalid("unknown associativity")),
+                };
+                Filter::Assoc(assoc, self.strs()?)
+            },
+            REJECT => Filter::Reject(self.str()?),
+            PREFER => Filter::Prefer(self.str()?),
+            _ => return Err(invalid("unknown filter kind")),
+        })
+    }
+
+    fn template(&mut self) -> Result<Template, Error> {
+        Ok(match self.u8()? {
+            MANY => Template::Many(self.str()?),
+            MANY1 => Template::Many1(self.str()?),
+            OPTIONAL => Template::Optional(self.str()?),
+            SEP_BY => Template::SepBy(self.str()?, self.str()?),
+            SEP_BY1 => Template::SepBy1(self.str()?, self.str()?),
+            _ => return Err(invalid("unknown template kind")),
+        })
+    }
+}
+
+impl<T> Grammar<T> {
+    // Compact binary form of the grammar, see GrammarBuilder::load
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut symbols: Vec<(u8, &str)> = Vec::new();
+        let mut index = HashMap::new();
+        for symbol in self.rules.iter().flat_map(|r| r.spec.iter()) {
+            if !index.contains_key(symbol.name()) {
+                index.insert(symbol.name(), symbols.len());
+                symbols.push(match **symbol {
+                    Symbol::NonTerm(ref name) => (NONTERM, name.as_str()),
+                    Symbol::Terminal(ref name, _) => (TERMINAL, name.as_str()),
+                });
+            }
+        }
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        put_str(&mut out, &self.start);
+        put_u32(&mut out, symbols.len());
+        for &(kind, name) in &symbols {
+            out.push(kind);
+            put_str(&mut out, name);
+        }
+        put_u32(&mut out, self.rules.len());
+        for rule in &self.rules {
+            put_str(&mut out, &rule.head);
+            put_u32(&mut out, rule.spec.len());
+            for symbol in &rule.spec {
+                put_u32(&mut out, index[symbol.name()]);
+            }
+            out.extend_from_slice(&rule.weight.to_bits().to_le_bytes());
+        }
+        let filters = self.filters.declared();
+        put_u32(&mut out, filters.len());
+        for filter in filters {
+            put_filter(&mut out, filter);
+        }
+        // sorted so equal grammars give the same bytes
+        let mut templates: Vec<_> = self.templates.iter().collect();
+        templates.sort_by_key(|&(name, _)| name);
+        put_u32(&mut out, templates.len());
+        for (name, template) in templates {
+            put_str(&mut out, name);
+            put_template(&mut out, template);
+        }
+        out
+    }
+}
+
+impl<T> GrammarBuilder<T> {
+    // Rebuild a grammar from Grammar::to_bytes. The builder is the registry
+    // of terminals: each one used by the grammar must already be added.
+    // Rules keep their order so their RuleIds don't change.
+    pub fn load(mut self, bytes: &[u8]) -> Result<Grammar<T>, Error> {
+        let mut reader = Reader{bytes, pos: 0};
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a grammar"));
+        }
+        if reader.u8()? != VERSION {
+            return Err(invalid("unknown version"));
+        }
+        let start = reader.str()?;
+        let mut symbols = Vec::new();
+        for _ in 0..reader.u32()? {
+            let kind = reader.u8()?;
+            let name = reader.str()?;
+            match kind {
+                NONTERM => self.add_nonterm(name.as_str(), true),
+                TERMINAL => (),
+                _ => return Err(invalid("unknown symbol kind")),
+            }
+            symbols.push((kind, name));
+        }
+        // heads of rules may be missing from the symbols (no rule uses them)
+        self.add_nonterm(start.as_str(), true);
+        for _ in 0..reader.u32()? {
+            let head = reader.str()?;
+            self.add_nonterm(head.as_str(), true);
+            let mut spec = Vec::new();
+            for _ in 0..reader.u32()? {
+                let (_, name) = symbols.get(reader.u32()?)
+                    .ok_or_else(|| invalid("bad symbol index"))?;
+                spec.push(name.as_str());
+            }
+            self.add_weighted_rule(head, &spec, reader.f64()?);
+        }
+        for _ in 0..reader.u32()? {
+            let filter = reader.filter()?;
+            self.add_filter(filter);
+        }
+        let mut templates = HashMap::new();
+        for _ in 0..reader.u32()? {
+            let name = reader.str()?;
+            templates.insert(name, reader.template()?);
+        }
+        if reader.pos != bytes.len() {
+            return Err(invalid("trailing bytes"));
+        }
+        let mut grammar = self.into_grammar(start)?;
+        if templates.keys().any(|name| grammar.rules_for(name).is_empty()) {
+            return Err(invalid("bad template symbol"));
+        }
+        grammar.templates = templates;
+        // registered terminals must not stand in for non-terminals
+        for symbol in grammar.rules.iter().flat_map(|r| r.spec.iter()) {
+            let loaded = symbols.iter().find(|s| s.1 == symbol.name());
+            match (&**symbol, loaded) {
+                (&Symbol::Terminal(_, _), Some(&(NONTERM, _))) |
+                (&Symbol::NonTerm(_), Some(&(TERMINAL, _))) =>
+                    return Err(Error::DuplicateSym(symbol.name().to_string())),
+                _ => (),
+            }
+        }
+        Ok(grammar)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::{Grammar, GrammarBuilder};
+    use parser::{EarleyParser, Error};
+    use trees::EarleyForest;
+
+    fn terminals() -> GrammarBuilder {
+        GrammarBuilder::default()
+          .terminal("n", |n| n.chars().all(|c| "1234".contains(c)))
+          .terminal("+", |n| n == "+")
+    }
+
+    fn grammar() -> Grammar {
+        // Sum -> Sum + Num | Num ; Num -> n | <e>
+        terminals()
+          .nonterm("Sum")
+          .nonterm("Num")
+          .weighted_rule("Sum", &["Sum", "+", "Num"], 0.25)
+          .rule("Sum", &["Num"])
+          .rule("Num", &["n"])
+          .rule::<_, &str>("Num", &[])
+          .into_grammar("Sum")
+          .expect("Bad grammar")
+    }
+
+    #[test]
+    fn roundtrip() {
+        let g = grammar();
+        let loaded = terminals().load(&g.to_bytes()).unwrap();
+        assert_eq!(loaded.start, "Sum");
+        assert_eq!(loaded.str_rules(), g.str_rules());
+        assert_eq!(loaded.rules[0].weight, 0.25);
+        assert!(loaded.is_nullable("Sum"));
+        assert_eq!(loaded.to_bytes(), g.to_bytes());
+        let p = EarleyParser::new(loaded);
+        assert!(p.parse("1 + 2 +".split_whitespace()).is_ok());
+
+        // E -> E + E | E * E | n, filters are kept
+        let g = terminals()
+          .terminal("*", |n| n == "*")
+          .nonterm("E")
+          .rule("E", &["E", "+", "E"])
+          .rule("E", &["E", "*", "E"])
+          .rule("E", &["n"])
+          .left_assoc(&["E -> E + E"])
+          .priority(&["E -> E * E"], &["E -> E + E"])
+          .reject("E -> E * E")
+          .prefer("E -> n")
+          .into_grammar("E")
+          .expect("Bad grammar");
+        let loaded = terminals().terminal("*", |n| n == "*")
+            .load(&g.to_bytes()).unwrap();
+        assert_eq!(loaded.filters.declared(), g.filters.declared());
+        assert_eq!(loaded.to_bytes(), g.to_bytes());
+        let p = EarleyParser::new(loaded);
+        let ptrees = p.parse("1 + 1 + 1".split_whitespace()).unwrap();
+        assert_eq!(EarleyForest::new(|_, _| ()).num_trees(&ptrees), Some(1));
+        assert_eq!(p.parse("1 * 1".split_whitespace()).unwrap_err(),
+                   Error::Filtered("E".to_string()));
+
+        // L -> sep_by(n, +), template instances are kept
+        let mut gb = terminals().nonterm("L");
+        let list = gb.add_sep_by("n", "+");
+        gb.add_rule("L", &[list.as_str()]);
+        let g = gb.into_grammar("L").expect("Bad grammar");
+        let loaded = terminals().load(&g.to_bytes()).unwrap();
+        assert_eq!(loaded.templates, g.templates);
+        assert_eq!(loaded.to_bytes(), g.to_bytes());
+        let mut ev = EarleyForest::new(|_, tok| tok.to_string());
+        ev.lists(&loaded, |items| items.join(" "),
+                 |list| list.split_whitespace().map(String::from).collect());
+        assert_eq!(ev.missing_actions(&loaded), vec![format!("L -> {}", list)]);
+    }
+
+    #[test]
+    fn missing_terminals() {
+        let bytes = grammar().to_bytes();
+        let registry = GrammarBuilder::default()
+            .terminal("n", |n| n == "1");
+        assert_eq!(registry.load(&bytes).unwrap_err(),
+                   Error::MissingSym("+".to_string()));
+        // a terminal can't replace a non-terminal
+        let registry = terminals().terminal("Num", |n| n == "1");
+        assert_eq!(registry.load(&bytes).unwrap_err(),
+                   Error::DuplicateSym("Num".to_string()));
+    }
+
+    #[test]
+    fn bad_data() {
+        let bytes = grammar().to_bytes();
+        assert_eq!(terminals().load(b"nope").unwrap_err(),
+                   Error::InvalidData("not a grammar".to_string()));
+        assert_eq!(terminals().load(&bytes[..bytes.len() - 1]).unwrap_err(),
+                   Error::InvalidData("truncated".to_string()));
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(terminals().load(&extra).unwrap_err(),
+                   Error::InvalidData("trailing bytes".to_string()));
+    }
+}
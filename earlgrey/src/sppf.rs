@@ -0,0 +1,302 @@
+#![deny(warnings)]
+
+use grammar::Rule;
+use items::{Item, Trigger};
+use parser::ParseTrees;
+use std::collections::{HashMap, HashSet};
+use std::hash;
+use std::rc::Rc;
+use std::sync::Arc;
+
+
+// Shared Packed Parse Forest: every sub-derivation (symbol over a span) is
+// a single node no matter how many trees use it. Ambiguity shows up as a node
+// having more than one packed alternative.
+pub type NodeId = usize;
+
+#[derive(Debug)]
+pub enum SppfNode<T = String> {
+    // A token scanned at 'pos' matching a terminal symbol
+    Terminal { symbol: String, lexeme: Rc<T>, pos: usize },
+    // A non-terminal recognized over start..end
+    Symbol { name: String, start: usize, end: usize, packed: Vec<Packed<T>> },
+    // A rule recognized up to 'dot' (binarizes long rules so prefixes share)
+    Intermediate { rule: Arc<Rule<T>>, dot: usize, start: usize, end: usize,
+                   packed: Vec<Packed<T>> },
+}
+
+// One way of deriving a node. Children are [left, right] where left is the
+// node for the rule prefix (if any) and right the last recognized symbol.
+// Epsilon rules have no children.
+#[derive(Debug)]
+pub struct Packed<T = String> {
+    pub rule: Arc<Rule<T>>,
+    pub children: Vec<NodeId>,
+}
+
+pub struct Sppf<T = String> {
+    nodes: Vec<SppfNode<T>>,
+    root: NodeId,
+}
+
+enum Key<T> {
+    Terminal(String, usize),
+    Symbol(String, usize, usize),
+    Intermediate(Rc<Item<T>>),
+}
+
+// Items hash and compare without their tokens, neither do Keys
+impl<T> hash::Hash for Key<T> {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        match *self {
+            Key::Terminal(ref symbol, pos) => (0, symbol, pos).hash(state),
+            Key::Symbol(ref name, start, end) =>
+                (1, name, start, end).hash(state),
+            Key::Intermediate(ref item) => (2, item).hash(state),
+        }
+    }
+}
+
+impl<T> PartialEq for Key<T> {
+    fn eq(&self, other: &Key<T>) -> bool {
+        match (self, other) {
+            (Key::Terminal(a, i), Key::Terminal(b, j)) => a == b && i == j,
+            (Key::Symbol(a, i, k), Key::Symbol(b, j, l)) =>
+                a == b && i == j && k == l,
+            (Key::Intermediate(a), Key::Intermediate(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl<T> Eq for Key<T> {}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl<T> SppfNode<T> {
+    // Alternative derivations of this node (terminals have none)
+    pub fn packed(&self) -> &[Packed<T>] {
+        match *self {
+            SppfNode::Terminal{..} => &[],
+            SppfNode::Symbol{ref packed, ..} => packed,
+            SppfNode::Intermediate{ref packed, ..} => packed,
+        }
+    }
+
+    pub fn is_ambiguous(&self) -> bool { self.packed().len() > 1 }
+}
+
+impl<T> Sppf<T> {
+    // Build the forest walking back-pointers of all complete start items
+    pub fn new(ptrees: &ParseTrees<T>) -> Sppf<T> {
+        let mut builder = SppfBuilder{
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            expanded: HashSet::new(),
+            pending: Vec::new(),
+        };
+        let mut root = None;
+        for item in &ptrees.0 {
+            root = builder.node_for(item);
+        }
+        // expand items until every reachable node has all its alternatives
+        while let Some((id, item)) = builder.pending.pop() {
+            builder.expand(id, &item);
+        }
+        Sppf{
+            nodes: builder.nodes,
+            root: root.expect("BUG: ParseTrees empty"),
+        }
+    }
+
+    pub fn root(&self) -> NodeId { self.root }
+
+    pub fn node(&self, id: NodeId) -> &SppfNode<T> { &self.nodes[id] }
+
+    pub fn len(&self) -> usize { self.nodes.len() }
+
+    pub fn is_empty(&self) -> bool { self.nodes.is_empty() }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+struct SppfBuilder<T> {
+    nodes: Vec<SppfNode<T>>,
+    index: HashMap<Key<T>, NodeId>,
+    expanded: HashSet<Rc<Item<T>>>,
+    pending: Vec<(NodeId, Rc<Item<T>>)>,
+}
+
+impl<T> SppfBuilder<T> {
+    fn intern(&mut self, key: Key<T>, node: SppfNode<T>) -> NodeId {
+        if let Some(&id) = self.index.get(&key) {
+            return id;
+        }
+        self.nodes.push(node);
+        self.index.insert(key, self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    // Node representing an item, predictions (dot = 0) have none.
+    // Items with a single recognized symbol are represented by that symbol.
+    fn node_for(&mut self, item: &Rc<Item<T>>) -> Option<NodeId> {
+        if item.complete() {
+            let id = self.intern(
+                Key::Symbol(item.rule.head.clone(), item.start, item.end),
+                SppfNode::Symbol{name: item.rule.head.clone(),
+                                 start: item.start, end: item.end,
+                                 packed: Vec::new()});
+            // several rules for the same symbol/span all pack into one node
+            if self.expanded.insert(item.clone()) {
+                self.pending.push((id, item.clone()));
+            }
+            Some(id)
+        } else if item.dot == 0 {
+            None
+        } else if item.dot == 1 {
+            item.source().iter()
+                .map(|(src, trig)| self.trigger_node(src, trig))
+                .last()
+        } else {
+            let id = self.intern(
+                Key::Intermediate(item.clone()),
+                SppfNode::Intermediate{rule: item.rule.clone(), dot: item.dot,
+                                       start: item.start, end: item.end,
+                                       packed: Vec::new()});
+            if self.expanded.insert(item.clone()) {
+                self.pending.push((id, item.clone()));
+            }
+            Some(id)
+        }
+    }
+
+    fn trigger_node(&mut self, source: &Rc<Item<T>>, trigger: &Trigger<T>)
+            -> NodeId {
+        match *trigger {
+            Trigger::Complete(ref item) =>
+                self.node_for(item).expect("BUG: trigger is a prediction"),
+            Trigger::Scan(ref lexeme) => {
+                let symbol = source.next_symbol()
+                    .expect("BUG: missing scan trigger symbol").name();
+                self.intern(
+                    Key::Terminal(symbol.to_string(), source.end),
+                    SppfNode::Terminal{symbol: symbol.to_string(),
+                                       lexeme: lexeme.clone(),
+                                       pos: source.end})
+            }
+        }
+    }
+
+    fn expand(&mut self, id: NodeId, item: &Rc<Item<T>>) {
+        let mut alternatives: Vec<Packed<T>> = Vec::new();
+        for (src, trig) in item.source().iter() {
+            let mut children: Vec<_> = self.node_for(src).into_iter().collect();
+            children.push(self.trigger_node(src, trig));
+            // triggers completing different rules may map to the same node
+            if alternatives.iter().all(|alt| alt.children != children) {
+                alternatives.push(Packed{rule: item.rule.clone(), children});
+            }
+        }
+        // complete items without back-pointers are epsilon rules
+        if alternatives.is_empty() {
+            alternatives.push(Packed{rule: item.rule.clone(),
+                                     children: Vec::new()});
+        }
+        match self.nodes[id] {
+            SppfNode::Symbol{ref mut packed, ..} => packed.extend(alternatives),
+            SppfNode::Intermediate{ref mut packed, ..} =>
+                packed.extend(alternatives),
+            SppfNode::Terminal{..} => unreachable!("BUG: expanding terminal"),
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::GrammarBuilder;
+    use parser::EarleyParser;
+    use trees::EarleyForest;
+    use super::{Sppf, SppfNode};
+
+    #[test]
+    fn shared_nodes() {
+        // S -> S S | b
+        let grammar = GrammarBuilder::default()
+          .nonterm("S")
+          .terminal("b", |n| n == "b")
+          .rule("S", &["S", "S"])
+          .rule("S", &["b"])
+          .into_grammar("S")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar);
+        let sppf = Sppf::new(&p.parse("b b b".split_whitespace()).unwrap());
+        // root packs both ways of splitting 'b b b'
+        match *sppf.node(sppf.root()) {
+            SppfNode::Symbol{ref name, start, end, ref packed} => {
+                assert_eq!((name.as_ref(), start, end), ("S", 0, 3));
+                assert_eq!(packed.len(), 2);
+            },
+            ref n => panic!("Unexpected root {:?}", n),
+        }
+        // 3 terminals, 6 S spans (0-1, 1-2, 2-3, 0-2, 1-3, 0-3)
+        let terminals = (0..sppf.len())
+            .filter(|&id| matches!(*sppf.node(id), SppfNode::Terminal{..}))
+            .count();
+        let symbols = (0..sppf.len())
+            .filter(|&id| matches!(*sppf.node(id), SppfNode::Symbol{..}))
+            .count();
+        assert_eq!(terminals, 3);
+        assert_eq!(symbols, 6);
+        assert_eq!(sppf.len(), 9);
+    }
+
+    #[test]
+    fn packed_dedup() {
+        // S -> X ; X -> A | B ; A -> a ; B -> a
+        let grammar = GrammarBuilder::default()
+          .nonterm("S")
+          .nonterm("X")
+          .nonterm("A")
+          .nonterm("B")
+          .terminal("a", |n| n == "a")
+          .rule("S", &["X"])
+          .rule("X", &["A"])
+          .rule("X", &["B"])
+          .rule("A", &["a"])
+          .rule("B", &["a"])
+          .into_grammar("S")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar);
+        let sppf = Sppf::new(&p.parse("a".split_whitespace()).unwrap());
+        // ambiguity is on X, S has a single way to derive it
+        let root = sppf.node(sppf.root());
+        assert!(!root.is_ambiguous());
+        assert!(sppf.node(root.packed()[0].children[0]).is_ambiguous());
+        // counting trees sees each of them once
+        let mut ev = EarleyForest::new(|_, _| 1);
+        for rule in &["S -> X", "X -> A", "X -> B", "A -> a", "B -> a"] {
+            ev.action(*rule, |n| n[0]);
+        }
+        assert_eq!(ev.eval_sppf(&sppf, |alts| alts.iter().sum()).unwrap(), 2);
+    }
+
+    #[test]
+    fn cyclic_forest() {
+        // A -> <empty> | B
+        // B -> A
+        let grammar = GrammarBuilder::default()
+          .nonterm("A")
+          .nonterm("B")
+          .rule::<_, &str>("A", &[])
+          .rule("A", &["B"])
+          .rule("B", &["A"])
+          .into_grammar("A")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar);
+        let sppf = Sppf::new(&p.parse("".split_whitespace()).unwrap());
+        assert!(sppf.node(sppf.root()).is_ambiguous());
+        assert_eq!(sppf.len(), 2);
+    }
+}
@@ -0,0 +1,113 @@
+#![deny(warnings)]
+
+use grammar::{Grammar, Rule};
+use parser::{EarleyParser, ParseTrees, Error};
+use std::fmt;
+
+
+// Steps of the parser that add items to the chart
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Step {
+    Prediction,
+    // includes items advanced over nullable symbols
+    Completion,
+    Scan,
+}
+
+// Work done by a parse, see ParseSession::stats
+#[derive(Clone,Debug,Default,PartialEq)]
+pub struct Stats {
+    // number of items of each StateSet
+    pub set_items: Vec<usize>,
+    // items added by each step
+    pub predictions: usize,
+    pub completions: usize,
+    pub scans: usize,
+    // items added for each rule, indexed by RuleId
+    pub rule_items: Vec<usize>,
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl Stats {
+    pub fn count<T>(&mut self, step: Step, rule: &Rule<T>) {
+        match step {
+            Step::Prediction => self.predictions += 1,
+            Step::Completion => self.completions += 1,
+            Step::Scan => self.scans += 1,
+        }
+        if self.rule_items.len() <= rule.id.0 {
+            self.rule_items.resize(rule.id.0 + 1, 0);
+        }
+        self.rule_items[rule.id.0] += 1;
+    }
+
+    pub fn items(&self) -> usize { self.set_items.iter().sum() }
+
+    // Up to 'n' rules that added the most items, with how many they added
+    pub fn top_rules<T>(&self, grammar: &Grammar<T>, n: usize)
+            -> Vec<(String, usize)> {
+        let mut rules: Vec<_> = grammar.rules.iter()
+            .map(|rule| (rule.to_string(),
+                         self.rule_items.get(rule.id.0).cloned().unwrap_or(0)))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        rules.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        rules.truncate(n);
+        rules
+    }
+}
+
+impl<T> EarleyParser<T> {
+    // Like parse, also returning what it took to parse the input (even if
+    // it failed) to find the rules that make a grammar slow.
+    pub fn parse_with_stats<S, SI>(&self, tok: SI)
+            -> (Result<ParseTrees<T>, Error>, Stats)
+            where T: fmt::Display, S: Into<T>, SI: Iterator<Item=S> {
+        let mut session = self.session();
+        for lexeme in tok {
+            if let Err(e) = session.push(lexeme) {
+                return (Err(e), session.stats());
+            }
+        }
+        (session.parse_trees(), session.stats())
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::GrammarBuilder;
+    use parser::EarleyParser;
+
+    #[test]
+    fn parse_stats() {
+        // Sum -> Sum + Num | Num ; Num -> n
+        let grammar = GrammarBuilder::default()
+          .nonterm("Sum")
+          .nonterm("Num")
+          .terminal("n", |n| n.chars().all(|c| "1234".contains(c)))
+          .terminal("+", |n| n == "+")
+          .rule("Sum", &["Sum", "+", "Num"])
+          .rule("Sum", &["Num"])
+          .rule("Num", &["n"])
+          .into_grammar("Sum")
+          .expect("Bad grammar");
+        let p = EarleyParser::new(grammar.clone());
+        let (trees, stats) = p.parse_with_stats("1 + 2".split_whitespace());
+        assert!(trees.is_ok());
+        assert_eq!(stats.set_items.len(), 4);
+        assert_eq!(stats.scans, 3);
+        assert_eq!(stats.items(),
+                   stats.predictions + stats.completions + stats.scans);
+        let top = stats.top_rules(&grammar, 1);
+        assert_eq!(top, vec![("Sum -> Sum + Num".to_string(), 5)]);
+        assert_eq!(stats.top_rules(&grammar, 10).len(), 3);
+
+        // stats of failed parses cover the input up to the error
+        let (trees, stats) = p.parse_with_stats("1 + + 2".split_whitespace());
+        assert!(trees.is_err());
+        assert_eq!(stats.set_items.len(), 3);
+    }
+}
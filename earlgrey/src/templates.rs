@@ -0,0 +1,200 @@
+#![deny(warnings)]
+
+use grammar::{Grammar, GrammarBuilder, Rule};
+use trees::{EarleyForest, SyncEarleyForest};
+
+
+// Rule templates, instantiated with symbols by GrammarBuilder::add_template
+// into a non-terminal with a generated name (see unique_symbol_name):
+//   Many(X)       X*        N -> <e> | N X
+//   Many1(X)      X+        N -> X | N X
+//   Optional(X)   X?        N -> <e> | X
+//   SepBy(X, S)   X (S X)*  N -> <e> | SepBy1(X, S)
+//   SepBy1(X, S)  or empty  N -> X | N S X
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub enum Template {
+    Many(String),
+    Many1(String),
+    Optional(String),
+    SepBy(String, String),
+    SepBy1(String, String),
+}
+
+// How a template rule builds its list: 'nested' takes the list built by
+// the only symbol, 'recursive' appends the last item to the list of the
+// first symbol (separators are dropped), otherwise items make the list.
+fn list_shape<T>(template: &Template, rule: &Rule<T>) -> (bool, bool) {
+    let nested = match *template {
+        Template::SepBy(_, _) => !rule.spec.is_empty(),
+        _ => false,
+    };
+    let recursive = rule.spec.first().map(|s| s.name()) == Some(&rule.head);
+    (nested, recursive)
+}
+
+fn list_action<ASTNode, L, I>((nested, recursive): (bool, bool),
+                              list: L, items: I)
+        -> impl Fn(Vec<ASTNode>) -> ASTNode
+        where L: Fn(Vec<ASTNode>) -> ASTNode,
+              I: Fn(ASTNode) -> Vec<ASTNode> {
+    move |mut args| {
+        if nested { return args.swap_remove(0); }
+        if !recursive { return list(args); }
+        let last = args.pop().expect("BUG: template rule without items");
+        let mut values = items(args.swap_remove(0));
+        values.push(last);
+        list(values)
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+impl<T> GrammarBuilder<T> {
+    pub fn add_many(&mut self, x: &str) -> String {
+        self.add_template(Template::Many(x.to_string()))
+    }
+
+    pub fn add_many1(&mut self, x: &str) -> String {
+        self.add_template(Template::Many1(x.to_string()))
+    }
+
+    pub fn add_optional(&mut self, x: &str) -> String {
+        self.add_template(Template::Optional(x.to_string()))
+    }
+
+    // eg: arguments of a call, gb.add_sep_by("Arg", ",")
+    pub fn add_sep_by(&mut self, x: &str, sep: &str) -> String {
+        self.add_template(Template::SepBy(x.to_string(), sep.to_string()))
+    }
+
+    pub fn add_sep_by1(&mut self, x: &str, sep: &str) -> String {
+        self.add_template(Template::SepBy1(x.to_string(), sep.to_string()))
+    }
+}
+
+impl<'a, ASTNode: Clone + 'a, T> EarleyForest<'a, ASTNode, T> {
+    // Actions for the template instances of 'grammar' flattening them back
+    // to lists. 'list' builds a node from the items (an absent Optional is
+    // empty) and 'items' takes them back from one built by 'list'.
+    pub fn lists<L, I>(&mut self, grammar: &Grammar<T>, list: L, items: I)
+            where L: Fn(Vec<ASTNode>) -> ASTNode + Clone + 'a,
+                  I: Fn(ASTNode) -> Vec<ASTNode> + Clone + 'a {
+        for (name, template) in &grammar.templates {
+            for rule in grammar.rules_for(name) {
+                let shape = list_shape(template, &rule);
+                self.action(rule.id,
+                            list_action(shape, list.clone(), items.clone()));
+            }
+        }
+    }
+}
+
+impl<'a, ASTNode: Clone + 'a, T> SyncEarleyForest<'a, ASTNode, T> {
+    pub fn lists<L, I>(&mut self, grammar: &Grammar<T>, list: L, items: I)
+            where L: Fn(Vec<ASTNode>) -> ASTNode + Clone + Send + Sync + 'a,
+                  I: Fn(ASTNode) -> Vec<ASTNode> + Clone + Send + Sync + 'a {
+        for (name, template) in &grammar.templates {
+            for rule in grammar.rules_for(name) {
+                let shape = list_shape(template, &rule);
+                self.action(rule.id,
+                            list_action(shape, list.clone(), items.clone()));
+            }
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use grammar::GrammarBuilder;
+    use parser::{EarleyParser, Error};
+    use trees::EarleyForest;
+    use super::Template;
+
+    #[derive(Clone,Debug,PartialEq)]
+    enum Node {
+        Leaf(String),
+        List(Vec<Node>),
+    }
+
+    fn items(node: Node) -> Vec<Node> {
+        match node {
+            Node::List(items) => items,
+            leaf => vec![leaf],
+        }
+    }
+
+    #[test]
+    fn sep_by() {
+        // Call -> id ( sep_by(Arg, ",") ) Opt(!) ; Arg -> id | Call
+        let mut gb = GrammarBuilder::default()
+            .nonterm("Call")
+            .nonterm("Arg")
+            .terminal("id", |n| n.chars().all(|c| c.is_alphabetic()))
+            .terminal("(", |n| n == "(")
+            .terminal(")", |n| n == ")")
+            .terminal(",", |n| n == ",")
+            .terminal("!", |n| n == "!");
+        let args = gb.add_sep_by("Arg", ",");
+        let bang = gb.add_optional("!");
+        assert_eq!(gb.add_sep_by("Arg", ","), args);
+        gb.add_rule("Call", &["id", "(", args.as_str(), ")", bang.as_str()]);
+        gb.add_rule("Arg", &["id"]);
+        gb.add_rule("Arg", &["Call"]);
+        let grammar = gb.into_grammar("Call").unwrap();
+        assert_eq!(grammar.templates[&args],
+                   Template::SepBy("Arg".to_string(), ",".to_string()));
+
+        let mut ev = EarleyForest::new(|_, tok| Node::Leaf(tok.to_string()));
+        ev.lists(&grammar, Node::List, items);
+        ev.action("Arg -> id", |mut n| n.swap_remove(0));
+        ev.action("Arg -> Call", |mut n| n.swap_remove(0));
+        ev.action(format!("Call -> id ( {} ) {}", args, bang).as_str(),
+                  |n| Node::List(vec![n[0].clone(), n[2].clone(),
+                                      n[4].clone()]));
+        assert!(ev.missing_actions(&grammar).is_empty());
+        let p = EarleyParser::new(grammar);
+        let tree = ev.eval(&p.parse("f ( a , g ( ) ! , b )".split_whitespace())
+                           .unwrap()).unwrap();
+        let leaf = |s: &str| Node::Leaf(s.to_string());
+        assert_eq!(tree, Node::List(vec![
+            leaf("f"),
+            Node::List(vec![
+                leaf("a"),
+                Node::List(vec![leaf("g"), Node::List(vec![]),
+                                Node::List(vec![leaf("!")])]),
+                leaf("b"),
+            ]),
+            Node::List(vec![]),
+        ]));
+    }
+
+    #[test]
+    fn many() {
+        // Words -> many1(w) many(n)
+        let mut gb = GrammarBuilder::default()
+            .nonterm("Words")
+            .terminal("w", |n| n.chars().all(|c| c.is_alphabetic()))
+            .terminal("n", |n| n.chars().all(|c| c.is_numeric()));
+        let words = gb.add_many1("w");
+        let nums = gb.add_many("n");
+        gb.add_rule("Words", &[words, nums]);
+        let grammar = gb.into_grammar("Words").unwrap();
+        let mut ev = EarleyForest::new(|_, tok| Node::Leaf(tok.to_string()));
+        ev.lists(&grammar, Node::List, items);
+        ev.action(grammar.rules_for("Words")[0].id, Node::List);
+        let p = EarleyParser::new(grammar);
+        let eval = |input: &str| p.parse(input.split_whitespace())
+            .and_then(|trees| ev.eval(&trees));
+        assert_eq!(format!("{:?}", eval("a b 1 2 3").unwrap()),
+                   "List([List([Leaf(\"a\"), Leaf(\"b\")]), \
+                    List([Leaf(\"1\"), Leaf(\"2\"), Leaf(\"3\")])])");
+        assert!(eval("1").is_err());
+
+        let mut gb = GrammarBuilder::default().nonterm("S");
+        gb.add_many("x");
+        assert_eq!(gb.into_grammar("S").unwrap_err(),
+                   Error::MissingSym("x".to_string()));
+    }
+}
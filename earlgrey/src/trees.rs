@@ -1,132 +1,675 @@
 #![deny(warnings)]
 
+use grammar::{Grammar, Rule, RuleId};
 use items::{Item, Trigger};
 use parser::{ParseTrees, Error};
-use std::collections::HashMap;
+use sppf::{Sppf, SppfNode, Packed, NodeId};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
 use std::rc::Rc;
 
 
-// Semantic actions to execute when walking the tree
-type SemAction<'a, ASTNode> = Box<Fn(Vec<ASTNode>) -> ASTNode + 'a>;
-// Given a Rule and a Token build an ASTNode
-type LeafBuilder<'a, ASTNode> = Box<Fn(&str, &str) -> ASTNode + 'a>;
+// Argument lists collected for each SPPF node, None while being evaluated
+type SppfMemo<ASTNode> = HashMap<NodeId, Option<Rc<Vec<Vec<ASTNode>>>>>;
+// Derivations ranked for each SPPF node, None while being ranked
+type KBestMemo<ASTNode> = HashMap<NodeId, Option<Ranking<ASTNode>>>;
+// Log of the weight of a derivation and the arguments it evaluates to
+type Ranked<ASTNode> = (f64, Rc<Vec<ASTNode>>);
+// Back-pointers of an item in the order trees are walked
+type Sources<T> = Rc<Vec<(Rc<Item<T>>, Trigger<T>)>>;
 
-pub struct EarleyForest<'a, ASTNode: Clone> {
-    actions: HashMap<String, SemAction<'a, ASTNode>>,
-    leaf_builder: LeafBuilder<'a, ASTNode>,
+// A way of deriving an SPPF node: log of its weight, the packed alternative
+// used and which derivation (rank) of each child it picked
+struct Derivation {
+    score: f64,
+    alt: usize,
+    ranks: Vec<usize>,
+}
+
+// Derivations of an SPPF node found so far (best first) with the arguments
+// they evaluate to, and the candidates to find the next ones. Derivations
+// rejected by actions are dropped as they're found. See Huang & Chiang,
+// Better k-best Parsing (lazy enumeration).
+struct Ranking<ASTNode> {
+    found: Vec<Ranked<ASTNode>>,
+    candidates: BinaryHeap<Derivation>,
+    seen: HashSet<(usize, Vec<usize>)>,
+}
+
+// Closures a forest runs when walking the tree: semantic actions (None
+// rejects the derivation) and the builder of leaves (given a Terminal name
+// and a Token build an ASTNode).
+// Local forests take any closure, Shared forests only take Send + Sync ones
+// so the forest can be used from several threads.
+pub trait Closures<'a, ASTNode, T> {
+    type Action: ?Sized + Fn(Vec<ASTNode>) -> Option<ASTNode>;
+    type LeafBuilder: ?Sized + Fn(&str, &T) -> ASTNode;
+}
+
+pub enum Local {}
+
+pub enum Shared {}
+
+impl<'a, ASTNode, T> Closures<'a, ASTNode, T> for Local {
+    type Action = Fn(Vec<ASTNode>) -> Option<ASTNode> + 'a;
+    type LeafBuilder = Fn(&str, &T) -> ASTNode + 'a;
+}
+
+impl<'a, ASTNode, T> Closures<'a, ASTNode, T> for Shared {
+    type Action = Fn(Vec<ASTNode>) -> Option<ASTNode> + Send + Sync + 'a;
+    type LeafBuilder = Fn(&str, &T) -> ASTNode + Send + Sync + 'a;
+}
+
+// Values returned by fallible actions, None or Err reject the derivation
+pub trait Fallible<ASTNode> {
+    fn accepted(self) -> Option<ASTNode>;
+}
+
+impl<ASTNode> Fallible<ASTNode> for Option<ASTNode> {
+    fn accepted(self) -> Option<ASTNode> { self }
+}
+
+impl<ASTNode, E> Fallible<ASTNode> for Result<ASTNode, E> {
+    fn accepted(self) -> Option<ASTNode> { self.ok() }
+}
+
+// What an action is registered for: a rule by its string representation
+// ("Sum -> Sum + Num"), a rule by its RuleId or, as a fallback, every rule
+// of a non-terminal. Strings convert to Rule keys, RuleIds to Id keys.
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub enum ActionKey {
+    Rule(String),
+    Id(RuleId),
+    Head(String),
+}
+
+impl<'s> From<&'s str> for ActionKey {
+    fn from(rule: &'s str) -> Self { ActionKey::Rule(rule.to_string()) }
+}
+
+impl<'s> From<&'s String> for ActionKey {
+    fn from(rule: &'s String) -> Self { ActionKey::Rule(rule.clone()) }
+}
+
+impl From<RuleId> for ActionKey {
+    fn from(id: RuleId) -> Self { ActionKey::Id(id) }
+}
+
+pub struct EarleyForest<'a, ASTNode: Clone, T = String, C = Local>
+        where C: Closures<'a, ASTNode, T> {
+    actions: HashMap<ActionKey, Box<C::Action>>,
+    leaf_builder: Box<C::LeafBuilder>,
     debug: bool,
 }
 
+// A forest that is Send + Sync, eg: to share one across a thread pool
+pub type SyncEarleyForest<'a, ASTNode, T = String> =
+    EarleyForest<'a, ASTNode, T, Shared>;
+
 impl<'a, ASTNode: Clone> EarleyForest<'a, ASTNode> {
     pub fn new<Builder>(leaf_builder: Builder) -> Self
-            where Builder: Fn(&str, &str) -> ASTNode + 'a {
+            where Builder: Fn(&str, &String) -> ASTNode + 'a {
+        EarleyForest::typed(leaf_builder)
+    }
+}
+
+impl<'a, ASTNode: Clone, T> EarleyForest<'a, ASTNode, T> {
+    // Forest over tokens of type T, leaves are built from the original token
+    pub fn typed<Builder>(leaf_builder: Builder) -> Self
+            where Builder: Fn(&str, &T) -> ASTNode + 'a {
         EarleyForest{
             actions: HashMap::new(),
             leaf_builder: Box::new(leaf_builder),
             debug: false}
     }
 
-    // Register semantic actions to act when rules are matched
-    pub fn action<Action>(&mut self, rule: &str, action: Action)
-            where Action: Fn(Vec<ASTNode>) -> ASTNode + 'a {
-        self.actions.insert(rule.to_string(), Box::new(action));
+    // Register semantic actions to act when rules are matched, see ActionKey
+    pub fn action<K, Action>(&mut self, key: K, action: Action)
+            where K: Into<ActionKey>, Action: Fn(Vec<ASTNode>) -> ASTNode + 'a {
+        self.actions.insert(key.into(),
+                            Box::new(move |args| Some(action(args))));
+    }
+
+    // Actions returning an Option or Result. Rejected values prune the
+    // derivation they belong to, other trees are still evaluated.
+    pub fn try_action<K, Action, R>(&mut self, key: K, action: Action)
+            where K: Into<ActionKey>, Action: Fn(Vec<ASTNode>) -> R + 'a,
+                  R: Fallible<ASTNode> {
+        self.actions.insert(key.into(),
+                            Box::new(move |args| action(args).accepted()));
     }
 }
 
-impl<'a, ASTNode: Clone> EarleyForest<'a, ASTNode> {
-    fn reduce(&self, root: &Rc<Item>, args: Vec<ASTNode>)
-            -> Result<Vec<ASTNode>, Error> {
+impl<'a, ASTNode: Clone> SyncEarleyForest<'a, ASTNode> {
+    pub fn new_sync<Builder>(leaf_builder: Builder) -> Self
+            where Builder: Fn(&str, &String) -> ASTNode + Send + Sync + 'a {
+        EarleyForest::typed_sync(leaf_builder)
+    }
+}
+
+impl<'a, ASTNode: Clone, T> SyncEarleyForest<'a, ASTNode, T> {
+    pub fn typed_sync<Builder>(leaf_builder: Builder) -> Self
+            where Builder: Fn(&str, &T) -> ASTNode + Send + Sync + 'a {
+        EarleyForest{
+            actions: HashMap::new(),
+            leaf_builder: Box::new(leaf_builder),
+            debug: false}
+    }
+
+    pub fn action<K, Action>(&mut self, key: K, action: Action)
+            where K: Into<ActionKey>,
+                  Action: Fn(Vec<ASTNode>) -> ASTNode + Send + Sync + 'a {
+        self.actions.insert(key.into(),
+                            Box::new(move |args| Some(action(args))));
+    }
+
+    pub fn try_action<K, Action, R>(&mut self, key: K, action: Action)
+            where K: Into<ActionKey>,
+                  Action: Fn(Vec<ASTNode>) -> R + Send + Sync + 'a,
+                  R: Fallible<ASTNode> {
+        self.actions.insert(key.into(),
+                            Box::new(move |args| action(args).accepted()));
+    }
+}
+
+impl<'a, ASTNode: Clone, T, C> EarleyForest<'a, ASTNode, T, C>
+        where C: Closures<'a, ASTNode, T> {
+    // None when the action rejected the arguments
+    fn reduce(&self, root: &Rc<Item<T>>, args: Vec<ASTNode>)
+            -> Result<Option<Vec<ASTNode>>, Error> {
         // if item is not complete, keep collecting args
-        if !root.complete() { return Ok(args) }
-        let rulename = root.rule.to_string();
-        match self.actions.get(&rulename) {
-            None => Err(Error::MissingAction(rulename)),
+        if !root.complete() { return Ok(Some(args)) }
+        Ok(self.apply(&root.rule, args)?.map(|value| vec![value]))
+    }
+
+    // Action for a rule: by id, by rule string and then by its head
+    fn action_for(&self, rule: &Rule<T>) -> Option<&C::Action> {
+        self.actions.get(&ActionKey::Id(rule.id))
+            .or_else(|| self.actions.get(&ActionKey::Rule(rule.to_string())))
+            .or_else(|| self.actions.get(&ActionKey::Head(rule.head.clone())))
+            .map(|action| &**action)
+    }
+
+    fn apply(&self, rule: &Rule<T>, args: Vec<ASTNode>)
+            -> Result<Option<ASTNode>, Error> {
+        match self.action_for(rule) {
+            None => Err(Error::MissingAction(rule.to_string())),
             Some(action) => {
-                if self.debug { eprintln!("Reduction: {}", rulename); }
-                Ok(vec![action(args)])
+                if self.debug { eprintln!("Reduction: {}", rule.to_string()); }
+                Ok(action(args))
             }
         }
     }
+
+    // Rules of 'grammar' that have no action, evaluating a tree using any of
+    // them fails with MissingAction. Check before parsing to catch typos.
+    pub fn missing_actions(&self, grammar: &Grammar<T>) -> Vec<String> {
+        grammar.rules.iter()
+            .filter(|rule| self.action_for(rule).is_none())
+            .map(|rule| rule.to_string())
+            .collect()
+    }
 }
 
-impl<'a, ASTNode: Clone> EarleyForest<'a, ASTNode> {
+impl<'a, ASTNode: Clone, T, C> EarleyForest<'a, ASTNode, T, C>
+        where C: Closures<'a, ASTNode, T> {
 
-    // Source is always a prediction, can't be anything else cause it's on the
-    // left side. Trigger is either a scan or a completion, only those can
-    // advance a prediction. To write this helper just draw a tree of the
-    // backpointers and see how they link
-    fn walker(&self, root: &Rc<Item>) -> Result<Vec<ASTNode>, Error> {
-        let mut args = Vec::new();
-        // collect arguments for semantic actions
-        let source = root.source();
-        if let Some((ref prediction, ref trigger)) = source.iter().next() {
-            // explore left side of the root
-            args.extend(self.walker(prediction)?);
-            // explore right side of the root
-            args.extend(match *trigger {
-                Trigger::Complete(ref item) => self.walker(item)?,
-                Trigger::Scan(ref token) => {
-                    let symbol = prediction.next_symbol()
-                        .expect("BUG: missing scan trigger symbol").name();
-                    vec![(self.leaf_builder)(&symbol, token)]
-                }
-            });
+    // for non-ambiguous grammars this retreieves the only possible parse,
+    // otherwise the first tree that actions didn't reject
+    pub fn eval(&self, ptrees: &ParseTrees<T>) -> Result<ASTNode, Error> {
+        let root = ptrees.0.first().expect("BUG: ParseTrees empty");
+        self.eval_iter(ptrees).next()
+            .unwrap_or_else(|| Err(Error::Rejected(root.rule.head.clone())))
+    }
+}
+
+
+impl<'a, ASTNode: Clone, T, C> EarleyForest<'a, ASTNode, T, C>
+        where C: Closures<'a, ASTNode, T> {
+
+    // Retrieves all parse trees
+    pub fn eval_all(&self, ptrees: &ParseTrees<T>)
+            -> Result<Vec<ASTNode>, Error> {
+        self.eval_iter(ptrees).collect()
+    }
+
+    // Lazily walk parse trees one at a time, see Trees
+    pub fn eval_iter<'f>(&'f self, ptrees: &ParseTrees<T>)
+            -> Trees<'f, 'a, ASTNode, T, C> {
+        Trees{
+            forest: self,
+            roots: ptrees.0.clone(),
+            root: 0,
+            choices: Vec::new(),
+            started: false,
+            done: false,
+            ordered: false,
+            sources: HashMap::new(),
         }
-        self.reduce(root, args)
     }
 
-    // for non-ambiguous grammars this retreieves the only possible parse
-    pub fn eval(&self, ptrees: &ParseTrees) -> Result<ASTNode, Error> {
-        // walker will always return a Vec of size 1 because root.complete
-        Ok(self.walker(ptrees.0.first().expect("BUG: ParseTrees empty"))?
-           .swap_remove(0))
+    // Exact number of trees eval_all would produce, computed on the
+    // back-pointer graph without enumerating them. Saturates at u64::MAX.
+    // None means infinitely many trees (a derivation cycles on itself).
+    pub fn num_trees(&self, ptrees: &ParseTrees<T>) -> Option<u64> {
+        let mut memo = HashMap::new();
+        ptrees.0.iter().try_fold(0u64, |total, root| {
+            Some(total.saturating_add(count_derivations(root, &mut memo)?))
+        })
     }
 }
 
+///////////////////////////////////////////////////////////////////////////////
 
-impl<'a, ASTNode: Clone> EarleyForest<'a, ASTNode> {
+// Iterator over the parse trees of a forest. Only the back-pointers picked
+// for the current tree are kept ('choices' in depth-first order), the next
+// tree is found by advancing the last choice with alternatives left, like an
+// odometer. Derivations that cycle on themselves or that an action rejected
+// are skipped.
+pub struct Trees<'f, 'a: 'f, ASTNode: Clone + 'f, T: 'f, C: 'f>
+        where C: Closures<'a, ASTNode, T> {
+    forest: &'f EarleyForest<'a, ASTNode, T, C>,
+    roots: Vec<Rc<Item<T>>>,
+    root: usize,
+    // (picked back-pointer, number of back-pointers) of each item walked
+    choices: Vec<(usize, usize)>,
+    started: bool,
+    done: bool,
+    ordered: bool,
+    sources: HashMap<Rc<Item<T>>, Sources<T>>,
+}
 
-    fn walker_all(&self, root: &Rc<Item>) -> Result<Vec<Vec<ASTNode>>, Error> {
-        let source = root.source();
-        if source.len() == 0 {
-            return Ok(vec![self.reduce(root, Vec::new())?]);
+impl<'f, 'a, ASTNode: Clone, T, C> Trees<'f, 'a, ASTNode, T, C>
+        where C: Closures<'a, ASTNode, T> {
+    // Yield trees in the same order across runs: roots by rule and
+    // back-pointers by split position. Otherwise the order is arbitrary.
+    pub fn ordered(mut self) -> Self {
+        self.ordered = true;
+        self.roots.sort_by_key(|root| root.rule.to_string());
+        self.root = 0;
+        self.choices.clear();
+        self.started = false;
+        self.sources.clear();
+        self
+    }
+
+    fn sources(&mut self, item: &Rc<Item<T>>) -> Sources<T> {
+        if let Some(sources) = self.sources.get(item) {
+            return sources.clone();
         }
-        let mut trees = Vec::new();
-        for (ref prediction, ref trigger) in source.iter() {
-            // get left-side-tree of each source
-            for mut args in self.walker_all(prediction)? {
-                match *trigger {
-                    Trigger::Complete(ref itm) => {
-                        // collect right-side-tree of each source
-                        for trig in self.walker_all(itm)? {
-                            let mut args = args.clone();
-                            args.extend(trig);
-                            trees.push(self.reduce(root, args)?);
-                        }
-                    },
-                    Trigger::Scan(ref token) => {
-                        let symbol = prediction.next_symbol()
-                            .expect("BUG: missing scan trigger symbol").name();
-                        args.push((self.leaf_builder)(&symbol, token));
-                        trees.push(self.reduce(root, args)?);
+        let mut sources: Vec<_> = item.source().iter().cloned().collect();
+        if self.ordered {
+            sources.sort_by_key(|(src, trig)| (src.end, match *trig {
+                Trigger::Complete(ref t) => t.rule.to_string(),
+                Trigger::Scan(_) => String::new(),
+            }));
+        }
+        let sources = Rc::new(sources);
+        self.sources.insert(item.clone(), sources.clone());
+        sources
+    }
+
+    // Source is always a prediction, can't be anything else cause it's on
+    // the left side. Trigger is either a scan or a completion, only those
+    // can advance a prediction. The current choices pick which back-pointer
+    // to follow, new choice points pick their first one.
+    // None if the tree has a cycle or an action rejected it.
+    fn walk(&mut self, item: &Rc<Item<T>>, depth: &mut usize,
+            path: &mut HashSet<*const Item<T>>)
+            -> Result<Option<Vec<ASTNode>>, Error> {
+        let sources = self.sources(item);
+        if sources.is_empty() {
+            return self.forest.reduce(item, Vec::new());
+        }
+        if !path.insert(&**item) { return Ok(None); }
+        if *depth == self.choices.len() {
+            self.choices.push((0, sources.len()));
+        }
+        let (ref prediction, ref trigger) = sources[self.choices[*depth].0];
+        *depth += 1;
+        let mut args = match self.walk(prediction, depth, path)? {
+            Some(args) => args,
+            None => return Ok(None),
+        };
+        match *trigger {
+            Trigger::Complete(ref completed) =>
+                match self.walk(completed, depth, path)? {
+                    Some(right) => args.extend(right),
+                    None => return Ok(None),
+                },
+            Trigger::Scan(ref token) => {
+                let symbol = prediction.next_symbol()
+                    .expect("BUG: missing scan trigger symbol").name();
+                args.push((self.forest.leaf_builder)(symbol, token));
+            }
+        }
+        path.remove(&(&**item as *const Item<T>));
+        self.forest.reduce(item, args)
+    }
+
+    // Move to the next combination of choices, false once exhausted
+    fn advance(&mut self) -> bool {
+        while let Some(&(picked, count)) = self.choices.last() {
+            if picked + 1 < count {
+                self.choices.last_mut().expect("BUG: no choices").0 += 1;
+                return true;
+            }
+            self.choices.pop();
+        }
+        false
+    }
+}
+
+impl<'f, 'a, ASTNode: Clone, T, C> Iterator for Trees<'f, 'a, ASTNode, T, C>
+        where C: Closures<'a, ASTNode, T> {
+    type Item = Result<ASTNode, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.root < self.roots.len() {
+            if self.started && !self.advance() {
+                self.root += 1;
+                self.started = false;
+                continue;
+            }
+            self.started = true;
+            let root = self.roots[self.root].clone();
+            let mut depth = 0;
+            match self.walk(&root, &mut depth, &mut HashSet::new()) {
+                Ok(Some(mut tree)) => return Some(Ok(tree.swap_remove(0))),
+                // choices past the cycle or rejection weren't walked
+                Ok(None) => self.choices.truncate(depth),
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+        None
+    }
+}
+
+// Iterative DFS over back-pointers. Items are memoized with None while
+// their sources are explored, finding one again means we're in a cycle.
+fn count_derivations<T>(root: &Rc<Item<T>>,
+                        memo: &mut HashMap<Rc<Item<T>>, Option<u64>>)
+        -> Option<u64> {
+    let mut stack = vec![(root.clone(), false)];
+    while let Some((item, explored)) = stack.pop() {
+        if explored {
+            let count = item.source().iter()
+                .map(|(src, trig)| {
+                    let left = memo[src].expect("BUG: source not counted");
+                    match *trig {
+                        Trigger::Complete(ref t) => left.saturating_mul(
+                            memo[t].expect("BUG: trigger not counted")),
+                        Trigger::Scan(_) => left,
+                    }
+                })
+                .fold(0u64, |acc, n| acc.saturating_add(n));
+            // items without back-pointers are predictions or epsilon rules
+            let count = if item.source().is_empty() { 1 } else { count };
+            memo.insert(item, Some(count));
+            continue;
+        }
+        match memo.get(&item) {
+            Some(&Some(_)) => continue,
+            Some(&None) => return None,
+            None => (),
+        }
+        memo.insert(item.clone(), None);
+        stack.push((item.clone(), true));
+        for (src, trig) in item.source().iter() {
+            stack.push((src.clone(), false));
+            if let Trigger::Complete(ref t) = *trig {
+                stack.push((t.clone(), false));
+            }
+        }
+    }
+    memo[root]
+}
+
+
+impl<'a, ASTNode: Clone, T, C> EarleyForest<'a, ASTNode, T, C>
+        where C: Closures<'a, ASTNode, T> {
+
+    // Symbol and Terminal nodes evaluate to a single argument, Intermediate
+    // nodes to the argument lists of every way their rule prefix was matched.
+    // Results are memoized so each packed node is only evaluated once.
+    fn sppf_walker<M>(&self, sppf: &Sppf<T>, id: NodeId, merge: &M,
+                      memo: &mut SppfMemo<ASTNode>)
+            -> Result<Option<Rc<Vec<Vec<ASTNode>>>>, Error>
+            where M: Fn(Vec<ASTNode>) -> ASTNode {
+        // nodes being evaluated are part of a cycle, skip those derivations
+        if let Some(args) = memo.get(&id) {
+            return Ok(args.clone());
+        }
+        memo.insert(id, None);
+        let args = match *sppf.node(id) {
+            SppfNode::Terminal{ref symbol, ref lexeme, ..} =>
+                vec![vec![(self.leaf_builder)(symbol, lexeme)]],
+            SppfNode::Symbol{ref packed, ..} => {
+                let mut values = Vec::new();
+                for alt in packed {
+                    for args in self.sppf_packed(sppf, alt, merge, memo)? {
+                        values.extend(self.apply(&alt.rule, args)?);
+                    }
+                }
+                match values.len() {
+                    0 => Vec::new(),
+                    1 => vec![values],
+                    _ => vec![vec![merge(values)]],
+                }
+            },
+            SppfNode::Intermediate{ref packed, ..} => {
+                let mut arglists = Vec::new();
+                for alt in packed {
+                    arglists.extend(self.sppf_packed(sppf, alt, merge, memo)?);
+                }
+                arglists
+            },
+        };
+        let args = if args.is_empty() { None } else { Some(Rc::new(args)) };
+        memo.insert(id, args.clone());
+        Ok(args)
+    }
+
+    // Argument lists resulting from combining a packed node's children
+    fn sppf_packed<M>(&self, sppf: &Sppf<T>, alt: &Packed<T>, merge: &M,
+                      memo: &mut SppfMemo<ASTNode>)
+            -> Result<Vec<Vec<ASTNode>>, Error>
+            where M: Fn(Vec<ASTNode>) -> ASTNode {
+        let mut arglists = vec![Vec::new()];
+        for &child in &alt.children {
+            let suffixes = match self.sppf_walker(sppf, child, merge, memo)? {
+                Some(suffixes) => suffixes,
+                None => return Ok(Vec::new()),
+            };
+            arglists = arglists.iter()
+                .flat_map(|prefix| suffixes.iter().map(move |suffix| {
+                    let mut args = prefix.clone();
+                    args.extend(suffix.iter().cloned());
+                    args
+                }))
+                .collect();
+        }
+        Ok(arglists)
+    }
+
+    // Evaluate a shared forest running actions once per packed node.
+    // Values of ambiguous nodes are combined by 'merge' before moving up,
+    // rejected values are left out.
+    pub fn eval_sppf<M>(&self, sppf: &Sppf<T>, merge: M)
+            -> Result<ASTNode, Error>
+            where M: Fn(Vec<ASTNode>) -> ASTNode {
+        let mut memo = HashMap::new();
+        match self.sppf_walker(sppf, sppf.root(), &merge, &mut memo)? {
+            Some(args) => Ok(args[0][0].clone()),
+            // the root always has a finite derivation, actions rejected it
+            None => match *sppf.node(sppf.root()) {
+                SppfNode::Symbol{ref name, ..} =>
+                    Err(Error::Rejected(name.clone())),
+                _ => unreachable!("BUG: SPPF root isn't a symbol"),
+            },
+        }
+    }
+}
+
+
+impl<'a, ASTNode: Clone, T, C> EarleyForest<'a, ASTNode, T, C>
+        where C: Closures<'a, ASTNode, T> {
+
+    // The 'rank' best derivation of a node that actions don't reject, with
+    // the arguments it evaluates to. Symbol and Terminal nodes are a single
+    // argument. None if the node has no more derivations.
+    fn kbest_nth(&self, sppf: &Sppf<T>, id: NodeId, rank: usize,
+                 memo: &mut KBestMemo<ASTNode>)
+            -> Result<Option<Ranked<ASTNode>>, Error> {
+        let mut ranking = match memo.insert(id, None) {
+            // nodes being ranked are part of a cycle, skip those derivations
+            Some(None) => return Ok(None),
+            Some(Some(ranking)) => ranking,
+            None => self.kbest_start(sppf, id, memo)?,
+        };
+        while ranking.found.len() <= rank {
+            let deriv = match ranking.candidates.pop() {
+                Some(deriv) => deriv,
+                None => break,
+            };
+            // next candidates take the next derivation of one child
+            for child in 0..deriv.ranks.len() {
+                let mut ranks = deriv.ranks.clone();
+                ranks[child] += 1;
+                if ranking.seen.insert((deriv.alt, ranks.clone())) {
+                    if let Some(next) =
+                            self.kbest_derive(sppf, id, deriv.alt, ranks, memo)? {
+                        ranking.candidates.push(next);
                     }
                 }
             }
+            if let Some(args) = self.kbest_args(sppf, id, &deriv, memo)? {
+                ranking.found.push((deriv.score, args));
+            }
         }
-        Ok(trees)
+        let nth = ranking.found.get(rank).cloned();
+        memo.insert(id, Some(ranking));
+        Ok(nth)
     }
 
-    // Retrieves all parse trees
-    pub fn eval_all(&self, ptrees: &ParseTrees) -> Result<Vec<ASTNode>, Error> {
+    // Candidates are the best derivation of each packed alternative
+    fn kbest_start(&self, sppf: &Sppf<T>, id: NodeId,
+                   memo: &mut KBestMemo<ASTNode>)
+            -> Result<Ranking<ASTNode>, Error> {
+        let mut ranking = Ranking{found: Vec::new(),
+                                  candidates: BinaryHeap::new(),
+                                  seen: HashSet::new()};
+        let node = sppf.node(id);
+        if let SppfNode::Terminal{ref symbol, ref lexeme, ..} = *node {
+            let leaf = (self.leaf_builder)(symbol, lexeme);
+            ranking.found.push((0.0, Rc::new(vec![leaf])));
+        }
+        for (alt, packed) in node.packed().iter().enumerate() {
+            let ranks = vec![0; packed.children.len()];
+            ranking.seen.insert((alt, ranks.clone()));
+            if let Some(deriv) = self.kbest_derive(sppf, id, alt, ranks, memo)? {
+                ranking.candidates.push(deriv);
+            }
+        }
+        Ok(ranking)
+    }
+
+    // Derivation of a node using the 'ranks' derivation of each child of its
+    // 'alt' packed node, None if some child has no such derivation.
+    // Symbol nodes add their rule's weight.
+    fn kbest_derive(&self, sppf: &Sppf<T>, id: NodeId, alt: usize,
+                    ranks: Vec<usize>, memo: &mut KBestMemo<ASTNode>)
+            -> Result<Option<Derivation>, Error> {
+        let node = sppf.node(id);
+        let packed = &node.packed()[alt];
+        let mut score = match *node {
+            SppfNode::Symbol{..} => packed.rule.weight.ln(),
+            _ => 0.0,
+        };
+        for (&child, &rank) in packed.children.iter().zip(&ranks) {
+            match self.kbest_nth(sppf, child, rank, memo)? {
+                Some((child_score, _)) => score += child_score,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(Derivation{score, alt, ranks}))
+    }
+
+    // Arguments resulting from a derivation, like sppf_walker. None if its
+    // action rejects them (or a child is being ranked, see kbest_nth).
+    fn kbest_args(&self, sppf: &Sppf<T>, id: NodeId, deriv: &Derivation,
+                  memo: &mut KBestMemo<ASTNode>)
+            -> Result<Option<Rc<Vec<ASTNode>>>, Error> {
+        let node = sppf.node(id);
+        let packed = &node.packed()[deriv.alt];
+        let mut args = Vec::new();
+        for (&child, &rank) in packed.children.iter().zip(&deriv.ranks) {
+            match self.kbest_nth(sppf, child, rank, memo)? {
+                Some((_, child_args)) => args.extend(child_args.iter().cloned()),
+                None => return Ok(None),
+            }
+        }
+        match *node {
+            SppfNode::Symbol{..} => Ok(self.apply(&packed.rule, args)?
+                                       .map(|value| Rc::new(vec![value]))),
+            _ => Ok(Some(Rc::new(args))),
+        }
+    }
+
+    // Up to k trees with the highest weight (product of their rules weights)
+    // in decreasing order. Each tree comes with its weight.
+    // Trees rejected by actions don't count, derivations rejected at a node
+    // are left out before ranking the nodes above.
+    pub fn eval_kbest(&self, ptrees: &ParseTrees<T>, k: usize)
+            -> Result<Vec<(ASTNode, f64)>, Error> {
+        let sppf = Sppf::new(ptrees);
+        let mut memo = HashMap::new();
         let mut trees = Vec::new();
-        for root in &ptrees.0 {
-            trees.extend(
-                self.walker_all(root)?.into_iter()
-                    .map(|mut treevec| treevec.swap_remove(0)));
+        while trees.len() < k {
+            match self.kbest_nth(&sppf, sppf.root(), trees.len(), &mut memo)? {
+                Some((score, args)) => trees.push((args[0].clone(), score.exp())),
+                None => break,
+            }
         }
         Ok(trees)
     }
 
-    // TODO: provide an estimate
-    pub fn num_trees(&self) -> Option<u32> { None }
+    // Viterbi parse: the most likely tree according to rule weights
+    pub fn eval_best(&self, ptrees: &ParseTrees<T>)
+            -> Result<(ASTNode, f64), Error> {
+        let root = ptrees.0.first().expect("BUG: ParseTrees empty");
+        self.eval_kbest(ptrees, 1)?.pop()
+            .ok_or_else(|| Error::Rejected(root.rule.head.clone()))
+    }
+}
+
+fn by_score(a: f64, b: f64) -> Ordering {
+    a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+}
+
+// Derivations are popped best score first, ties go to earlier alternatives
+// and lower ranks so trees come out in a stable order
+impl Ord for Derivation {
+    fn cmp(&self, other: &Derivation) -> Ordering {
+        by_score(self.score, other.score)
+            .then_with(|| other.alt.cmp(&self.alt))
+            .then_with(|| other.ranks.cmp(&self.ranks))
+    }
+}
+
+impl PartialOrd for Derivation {
+    fn partial_cmp(&self, other: &Derivation) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
+
+impl PartialEq for Derivation {
+    fn eq(&self, other: &Derivation) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Derivation {}
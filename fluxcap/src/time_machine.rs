@@ -10,7 +10,7 @@ type DateTime = chrono::NaiveDateTime;
 type Date = chrono::NaiveDate;
 
 use self::lexers::DelimTokenizer;
-use self::earlgrey::{EarleyParser, EarleyForest};
+use self::earlgrey::{EarleyParser, SyncEarleyForest};
 use self::abackus::ParserBuilder;
 use self::kronos::constants as kc;
 use self::kronos::{Seq, Grain, TimeDir, Range};
@@ -83,9 +83,9 @@ fn time_parser() -> EarleyParser {
         .unwrap_or_else(|e| panic!("TimeMachine grammar BUG: {:?}", e))
 }
 
-fn time_evaler<'a>() -> EarleyForest<'a, T> {
+fn time_evaler<'a>() -> SyncEarleyForest<'a, T> {
     // provide a function that evaluates tokens
-    let mut ev = EarleyForest::new(|terminal, t| match terminal {
+    let mut ev = SyncEarleyForest::new_sync(|terminal, t| match terminal {
         "weekday" => T::Seq(Seq::weekday(kc::weekday(t).unwrap())),
         "month" => T::Seq(Seq::month(kc::month(t).unwrap())),
         "ordinal" => T::Ord(kc::ordinal(t).or(kc::short_ordinal(t)).unwrap()),
@@ -108,10 +108,12 @@ fn time_evaler<'a>() -> EarleyForest<'a, T> {
             $p(v0, v1) => (v0, v1), _ => panic!("Bad pull match") })}
 
     // add semantic time rules
-    ev.action("mday -> ordinal", |mut n| {
+    // months have at most 31 days, reject other readings of the ordinal
+    ev.try_action("mday -> ordinal", |mut n| {
         let n = x!(T::Ord, n.remove(0));
-        T::Seq(Seq::nthof(n, Seq::grain(Grain::Day),
-                   Seq::grain(Grain::Month)))
+        if !(1..=31).contains(&n) { return None; }
+        Some(T::Seq(Seq::nthof(n, Seq::grain(Grain::Day),
+                        Seq::grain(Grain::Month))))
     });
 
     ev.action("seq -> mday", |mut n| n.remove(0));
@@ -351,7 +353,7 @@ impl T {
 }
 
 
-pub struct TimeMachine<'a>(EarleyParser, EarleyForest<'a, T>);
+pub struct TimeMachine<'a>(EarleyParser, SyncEarleyForest<'a, T>);
 
 impl<'a> TimeMachine<'a> {
     pub fn new() -> TimeMachine<'a> {
@@ -369,7 +371,7 @@ impl<'a> TimeMachine<'a> {
         let state = match self.0.parse(&mut tokenizer) {
             Ok(state) => state,
             Err(e) => {
-                eprintln!("TimeMachine {:?} for '{}'", e, time);
+                eprintln!("TimeMachine {} for '{}'", e, time);
                 return Vec::new();
             }
         };
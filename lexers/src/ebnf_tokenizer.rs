@@ -23,7 +23,7 @@ impl Iterator for EbnfTokenizer {
         }
         let mut s = &mut self.0;
         s.ignore_ws();
-        if s.accept_any_char("[]{}()|;").is_some() {
+        if s.accept_any_char("[]{}()|;+*?-").is_some() {
             return Some(s.extract_string());
         }
         let backtrack = s.pos();
@@ -33,6 +33,14 @@ impl Iterator for EbnfTokenizer {
             }
             s.set_pos(backtrack);
         }
+        // range of chars: 'a'..'z'
+        let backtrack = s.pos();
+        if s.accept_any_char(".").is_some() {
+            if s.accept_any_char(".").is_some() {
+                return Some(s.extract_string());
+            }
+            s.set_pos(backtrack);
+        }
         let backtrack = s.pos();
         if let Some(q) = s.accept_any_char("\"'") {
             while let Some(n) = s.next() {
@@ -55,3 +63,18 @@ impl Iterator for EbnfTokenizer {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EbnfTokenizer;
+
+    #[test]
+    fn test_ebnf_tokenizer() {
+        let tokens: Vec<_> = EbnfTokenizer::scanner(
+            "id := ( a | 'x' )+ b* [c]? d - \"e\" 'a'..'z' ;").collect();
+        assert_eq!(tokens, vec![
+            "id", ":=", "(", "a", "|", "'", "x", "'", ")", "+", "b", "*",
+            "[", "c", "]", "?", "d", "-", "\"", "e", "\"",
+            "'", "a", "'", "..", "'", "z", "'", ";"]);
+    }
+}